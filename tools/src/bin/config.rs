@@ -0,0 +1,69 @@
+use std::{path::PathBuf, process::exit};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use slog::Logger;
+use sloggers::{
+    terminal::{Destination, TerminalLoggerBuilder},
+    types::Severity,
+    Build,
+};
+use tools::{
+    current_meta, err, exit_code, info, read_bytes,
+    runtime::{apply_runtime_config, effective_meta, parse_runtime_config, redacted},
+    ErrorKind, RW_STATE_DIR,
+};
+
+#[derive(Parser, Debug)]
+#[clap()]
+struct Args {
+    // Validate this file as a runtime config and show the result of applying it, instead of the
+    // installed runtime config
+    #[clap(long)]
+    pub check: Option<PathBuf>,
+}
+
+fn main_inner(log: Logger) -> Result<()> {
+    let args = Args::parse();
+    let current = current_meta()?;
+    let effective = match &args.check {
+        Some(path) => {
+            let config = parse_runtime_config(&read_bytes(path)?).context(ErrorKind::Config)?;
+            let effective = apply_runtime_config(&log, current, Some(config))?;
+            info!(log, "Runtime config is valid");
+            effective
+        }
+        None => effective_meta(&log, current, &PathBuf::from(RW_STATE_DIR))?,
+    };
+    println!("{}", serde_json::to_string_pretty(&redacted(&effective))?);
+    Ok(())
+}
+
+fn main() {
+    fn main0() -> i32 {
+        let mut builder = TerminalLoggerBuilder::new();
+        builder.level(Severity::Debug);
+        builder.destination(Destination::Stderr);
+        let root_log = builder.build().unwrap();
+        match main_inner(root_log.clone()) {
+            Ok(_) => {
+                return 0;
+            }
+            Err(e) => {
+                let code = exit_code(&e);
+                err!(
+                    root_log,
+                    "Exiting with error",
+                    kind = ErrorKind::of(&e).map(|k| k.name()).unwrap_or("other"),
+                    code = code,
+                    err = format!("{:?}", e)
+                );
+                return code;
+            }
+        };
+    }
+    let code = main0();
+    if code != 0 {
+        exit(code);
+    }
+}
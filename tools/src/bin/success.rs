@@ -1,24 +1,69 @@
-use std::process::{exit, Command};
+use std::{path::Path, process::exit};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::Duration;
 use slog::Logger;
 use sloggers::{
     terminal::{Destination, TerminalLoggerBuilder},
     types::Severity,
     Build,
 };
-use tools::{current_meta, mount_boot, SimpleCommand};
-use tools::{err, info};
+use tools::{
+    current_meta, ec, exit_code, grub_env_list, grub_env_set, grub_env_unset, health::check_health,
+    http::HttpClient, lease::Leases, mount_boot, retry, runtime::effective_meta, ErrorKind, Host,
+    InternalMeta, RetryPolicy, GRUB_ATTEMPTS_VAR, GRUB_NEW_VAR, GRUB_SAVED_VAR, RW_STATE_DIR,
+};
+use tools::{err, info, warn};
 
 fn main_inner(log: Logger) -> Result<()> {
+    let host = Host::system();
     let current = current_meta()?;
-    let _mount = mount_boot(log.clone())?;
-    Command::new("grub-set-default").arg(current.uuid).run()?;
+    let http = HttpClient::new(&current.http)
+        .context(ErrorKind::Config)?
+        .with_timeout(Duration::seconds(10).to_std().unwrap());
+    info!(log, "Running health checks");
+    retry(
+        &log,
+        &RetryPolicy::fixed(current.health.deadline(), Duration::seconds(10)),
+        || check_health(&log, &*host.runner, &http, &current.health),
+    )
+    .context(ErrorKind::Health)?;
+    info!(log, "Health checks passed, marking boot successful");
+    ec!(("Error marking boot successful"), {
+        let _mount = mount_boot(log.clone(), &host)?;
+        let pending = grub_env_list(&host)?.remove(GRUB_NEW_VAR);
+        grub_env_set(&host, &[(GRUB_SAVED_VAR, &current.uuid)])?;
+        // Leave any newer version installed during this boot pending
+        if pending.is_none() || pending.as_ref() == Some(&current.uuid) {
+            grub_env_unset(&host, &[GRUB_ATTEMPTS_VAR, GRUB_NEW_VAR])?;
+        }
+        Ok(())
+    })
+    .context(ErrorKind::Bootloader)?;
+    // The boot is already marked successful, if the bucket can't be reached the updater releases
+    // the lease on its next check
+    if let Err(e) = release_lease(&log, current) {
+        warn!(
+            log,
+            "Failed to release reboot lease, leaving it to the updater",
+            err = format!("{:?}", e)
+        );
+    }
+    Ok(())
+}
+
+// Let the next device in the group reboot, with the device's credentials if overridden
+fn release_lease(log: &Logger, current: InternalMeta) -> Result<()> {
+    let state_dir = Path::new(RW_STATE_DIR);
+    let current = effective_meta(log, current, state_dir)?;
+    if let Some(leases) = Leases::system(log.clone(), &current, state_dir)? {
+        leases.release_held(state_dir)?;
+    }
     Ok(())
 }
 
 fn main() {
-    fn main0() -> bool {
+    fn main0() -> i32 {
         let mut builder = TerminalLoggerBuilder::new();
         builder.level(Severity::Debug);
         builder.destination(Destination::Stderr);
@@ -26,15 +71,23 @@ fn main() {
         match main_inner(root_log.clone()) {
             Ok(_) => {
                 info!(root_log, "Done");
-                return true;
+                return 0;
             }
             Err(e) => {
-                err!(root_log, "Exiting with error", err = format!("{:?}", e));
-                return false;
+                let code = exit_code(&e);
+                err!(
+                    root_log,
+                    "Exiting with error",
+                    kind = ErrorKind::of(&e).map(|k| k.name()).unwrap_or("other"),
+                    code = code,
+                    err = format!("{:?}", e)
+                );
+                return code;
             }
         };
     }
-    if !main0() {
-        exit(1);
+    let code = main0();
+    if code != 0 {
+        exit(code);
     }
 }
@@ -1,177 +1,118 @@
 use anyhow::{anyhow, Context, Result};
-use askama::Template;
 use chrono::Duration;
-use sha2::{Digest, Sha256};
+use clap::Parser;
 use slog::Logger;
 use sloggers::{
     terminal::{Destination, TerminalLoggerBuilder},
     types::Severity,
     Build,
 };
-use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    path::{Path, PathBuf},
-    process::{exit, Command},
-    str::FromStr,
-};
-use tools::mount_boot;
+use std::{path::PathBuf, process::exit};
 use tools::{
-    current_meta, ec, err, file_digest, find_root_parts, has_internet_gw, info, retry,
-    version_bucket, ExternalMeta, InternalMeta, ProxyWrite, SimpleCommand,
+    control::Control,
+    current_meta,
+    directives::Directives,
+    err, exit_code,
+    image::DiskImage,
+    info,
+    lease::Leases,
+    notify::Notifier,
+    runtime::effective_meta,
+    system::{version_source, Grub, NoReboot, SystemDisks, SystemReboot},
+    updater::{Reboot, Updater},
+    warn, ErrorKind, Host, RW_STATE_DIR,
 };
-use zstd::stream::raw::Decoder;
-use zstd::stream::zio::Writer;
 
-#[derive(Template)]
-#[template(path = "grub_two.conf", escape = "none")]
-struct GrubTemplate<'a> {
-    new: &'a InternalMeta,
-    current: &'a InternalMeta,
+#[derive(Parser, Debug)]
+#[clap()]
+struct Args {
+    // Update a disk image file instead of the running system
+    #[clap(long)]
+    pub image: Option<PathBuf>,
+    // Only download and verify the new version, recording it as staged
+    #[clap(long, conflicts_with = "activate")]
+    pub stage: bool,
+    // Only activate and reboot into the staged version
+    #[clap(long, conflicts_with = "serve")]
+    pub activate: bool,
+    // Keep running, serving the control socket and updating when clients ask
+    #[clap(long, conflicts_with = "image")]
+    pub serve: bool,
 }
 
 fn main_inner(log: Logger) -> Result<()> {
-    let current = current_meta()?;
-    ec!(
-        (
-            "Failed to update image from {}/{}",
-            current.bucket,
-            current.object_path
-        ),
-        {
-            // Wait for internet
-            retry(&log, Duration::minutes(10), Duration::seconds(10), || {
-                info!(log, "Waiting for route to internet...");
-                if has_internet_gw()? {
-                    return Ok(());
-                }
-                return Err(anyhow!("No default route found yet"));
-            })?;
-
-            // Get info on candidate version
-            let bucket = version_bucket(&current)?;
-
-            let new: ExternalMeta = ec!(
-                ("Error fetching new version meta"),
-                Ok(serde_json::from_slice(
-                    bucket
-                        .get_object(format!("{}.meta", current.object_path))
-                        .context("Failed to download meta for new version")?
-                        .bytes(),
-                )?)
-            )?;
-            if current.uuid == new.internal.uuid {
-                info!(
-                    log,
-                    "Latest version on file server matches currently booted version",
-                    uuid = &new.internal.uuid
-                );
-                return Ok(());
-            }
-            info!(
-                log,
-                "A new version was found, proceeding with update",
-                current = &current.uuid,
-                new = &new.internal.uuid
-            );
-
-            // Identify current and alt root partitions
-            let mut found_current = false;
-            let mut found_other_part = None;
-            let (root_disk, root_parts) = find_root_parts(&log)?;
-            for part in root_parts {
-                if let Some(_) = part.mountpoint {
-                    found_current = true;
-                } else {
-                    found_other_part = Some(PathBuf::from_str(&part.path)?);
-                    let other_digest = file_digest(Path::new(&part.path), new.size)?;
-                    if other_digest == new.sha256 {
-                        info!(log, "Digest of alternate partition matches new digest, must have fallen back. Aborting", digest=&new.sha256);
-                        return Ok(());
-                    }
-                    info!(log, "Hash of alternate partition", sha = other_digest);
-                }
-            }
-            if !found_current {
-                return Err(anyhow!("Unable to find mounted root device"));
-            }
-            let other_path =
-                found_other_part.ok_or_else(|| anyhow!("Unable to find alternate root device"))?;
-
-            // Install + check more things
-            info!(log, "Downloading new image");
-            let mut digest = Sha256::new();
-            ec!(
-                (
-                    "Error downloading new image to {}",
-                    other_path.to_string_lossy()
-                ),
-                {
-                    let mut proxy = ProxyWrite {
-                        a: &mut digest,
-                        b: &mut File::create(&other_path)
-                            .context("Failed to open {} for writing")?,
-                    };
-                    let mut buf_writer = BufWriter::new(&mut proxy);
-                    let mut writer = Writer::new(&mut buf_writer, Decoder::new().unwrap());
-                    bucket
-                        .get_object_to_writer(&new.internal.object_path, &mut writer)
-                        .context("Error downloading image")?;
-                    writer.finish().context("Failed to flush/finish output")?;
-                    Ok(())
-                }
-            )?;
-            let download_digest = format!("{:x}", digest.finalize());
-            if download_digest != new.sha256 {
-                return Err(anyhow!(
-                    "Downloaded digest {} doesn't match reported digest on server {}",
-                    download_digest,
-                    new.sha256
-                ));
-            }
-
-            // Update the grub
-            info!(log, "Updating grub");
-            let grub_cfg_path = "/boot/grub/grub.cfg";
-            ec!(
-                (
-                    "Error updating grub on {} with config {}",
-                    &root_disk.path,
-                    &grub_cfg_path
-                ),
-                {
-                    let _mount = mount_boot(log.clone())?;
-                    File::create(grub_cfg_path)
-                        .context("Failed to open grub config for writing")?
-                        .write_all(
-                            GrubTemplate {
-                                current: &current,
-                                new: &new.internal,
-                            }
-                            .render()
-                            .unwrap()
-                            .as_ref(),
-                        )
-                        .context("Failed to write grub file contents")?;
-                    Command::new("grub-install")
-                        .arg("--target=i386-pc")
-                        .arg(&root_disk.path)
-                        .run()?;
-                    Ok(())
-                }
-            )?;
-
-            // Reboot into new version
-            info!(log, "Grub installed successfully, rebooting in 15s");
-            std::thread::sleep(Duration::seconds(15).to_std().unwrap());
-            Command::new("reboot").run()?;
-            Ok(()) // dead code
+    let args = Args::parse();
+    let system = Host::system();
+    let image;
+    let (host, current, state_dir, reboot): (_, _, _, Box<dyn Reboot>) = match &args.image {
+        Some(path) => {
+            image = DiskImage::attach(log.clone(), &system, path).context(ErrorKind::Disk)?;
+            (
+                image.host(),
+                image.current_meta().context(ErrorKind::Config)?,
+                image.state_dir(),
+                Box::new(NoReboot),
+            )
         }
-    )
+        None => (
+            system.clone(),
+            current_meta()?,
+            PathBuf::from(RW_STATE_DIR),
+            Box::new(SystemReboot::new(system.runner.clone())),
+        ),
+    };
+    let current = effective_meta(&log, current, &state_dir)?;
+    let source = version_source(log.clone(), &current, state_dir.clone())?;
+    let mut updater = Updater::new(
+        log.clone(),
+        current,
+        state_dir,
+        source,
+        Box::new(Grub::new(log.clone(), host.clone())),
+        Box::new(SystemDisks::new(log.clone(), host)),
+        reboot,
+    );
+    updater.notifier = Notifier::from_env(log.clone());
+    if args.image.is_some() {
+        updater.reboot_delay = Duration::zero();
+    } else {
+        // An image isn't rebooted, so there's nothing to coordinate
+        updater.leases = Leases::system(log.clone(), &updater.current, &updater.state_dir)?;
+        updater.directives = Directives::system(
+            log.clone(),
+            &updater.current,
+            &updater.state_dir,
+            system.runner.clone(),
+        )?;
+    }
+    if args.serve {
+        let config = updater.current.control.clone().ok_or_else(|| {
+            anyhow!("--serve needs a control socket configured").context(ErrorKind::Config)
+        })?;
+        let control = Control::new(log.clone(), config, &updater.current.uuid);
+        control.listen().context(ErrorKind::Config)?;
+        updater.control = Some(control);
+        return updater.serve(args.stage);
+    }
+    // The update runs anyway, so only a reboot changes what happens next. If the network never
+    // came up the update would wait for it again, and fail the same way.
+    match updater.run_directives() {
+        Ok(r) if r.reboot => return Ok(()),
+        Ok(_) => {}
+        Err(e) if ErrorKind::of(&e) == Some(ErrorKind::Network) => return Err(e),
+        Err(e) => warn!(log, "Failed to run directives", err = format!("{:?}", e)),
+    }
+    if args.stage {
+        return updater.run_stage();
+    }
+    if args.activate {
+        return updater.run_activate();
+    }
+    updater.run()
 }
 
 fn main() {
-    fn main0() -> bool {
+    fn main0() -> i32 {
         let mut builder = TerminalLoggerBuilder::new();
         builder.level(Severity::Debug);
         builder.destination(Destination::Stderr);
@@ -179,15 +120,23 @@ fn main() {
         match main_inner(root_log.clone()) {
             Ok(_) => {
                 info!(root_log, "Done");
-                return true;
+                return 0;
             }
             Err(e) => {
-                err!(root_log, "Exiting with error", err = format!("{:?}", e));
-                return false;
+                let code = exit_code(&e);
+                err!(
+                    root_log,
+                    "Exiting with error",
+                    kind = ErrorKind::of(&e).map(|k| k.name()).unwrap_or("other"),
+                    code = code,
+                    err = format!("{:?}", e)
+                );
+                return code;
             }
         };
     }
-    if !main0() {
-        exit(1);
+    let code = main0();
+    if code != 0 {
+        exit(code);
     }
 }
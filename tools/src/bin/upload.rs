@@ -1,6 +1,7 @@
 use std::{fs::File, path::PathBuf, process::exit, str::FromStr};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, Utc};
 use clap::Parser;
 use s3::{creds::Credentials, Bucket};
 use sloggers::{
@@ -8,8 +9,11 @@ use sloggers::{
     types::Severity,
     Build,
 };
-use tools::{ec, err, info};
-use tools::{read_bytes, ExternalMeta};
+use tools::{
+    directives::{Action, Directive, DirectiveList, DirectivesConfig},
+    ec, err, info,
+};
+use tools::{exit_code, read_bytes, ErrorKind, ExternalMeta};
 
 #[derive(Parser, Debug)]
 #[clap()]
@@ -17,32 +21,125 @@ struct Args {
     #[clap()]
     version_meta: PathBuf,
 
-    #[clap()]
-    image: PathBuf,
+    #[clap(required_unless_present = "directive")]
+    image: Option<PathBuf>,
+
+    // Instead of uploading, add a directive for this device id to the version's bucket
+    #[clap(long, conflicts_with = "image", requires = "action")]
+    directive: Option<String>,
+
+    // reboot, check, reset_var or upload_logs
+    #[clap(long)]
+    action: Option<String>,
+
+    // Journal lines for upload_logs
+    #[clap(long)]
+    lines: Option<u32>,
+
+    // Seconds after which the directive is no longer run
+    #[clap(long)]
+    expires_in: Option<u64>,
+}
+
+fn parse_action(args: &Args) -> Result<Action> {
+    let name = args.action.as_deref().unwrap_or_default();
+    let action = match name {
+        "reboot" => Action::Reboot,
+        "check" => Action::Check,
+        "reset_var" => Action::ResetVar,
+        "upload_logs" => Action::UploadLogs { lines: args.lines },
+        _ => return Err(anyhow!("Unknown action {:?}", name)),
+    };
+    if args.lines.is_some() && name != "upload_logs" {
+        return Err(anyhow!("--lines only applies to upload_logs"));
+    }
+    Ok(action)
+}
+
+// Adds a directive to the device's directives object, dropping expired ones
+fn add_directive(bucket: &Bucket, config: &DirectivesConfig, args: &Args) -> Result<()> {
+    let device = args.directive.clone().unwrap();
+    let action = parse_action(args).context(ErrorKind::Config)?;
+    let key = format!(
+        "{}/{}/directives.json",
+        config.prefix.trim_end_matches('/'),
+        device
+    );
+    ec!(("Error adding directive to {}", key), {
+        let existing = bucket.get_object(&key)?;
+        let mut list = match existing.status_code() {
+            404 => DirectiveList {
+                device: device.clone(),
+                directives: vec![],
+            },
+            200 => serde_json::from_slice(existing.bytes())
+                .context("Existing directives object is invalid")?,
+            code => return Err(anyhow!("Getting directives object failed with {}", code)),
+        };
+        let now = Utc::now();
+        list.directives.retain(|d| {
+            serde_json::from_value::<Directive>(d.clone())
+                .map(|d| d.expires.map(|e| e > now).unwrap_or(true))
+                .unwrap_or(true)
+        });
+        let directive = Directive {
+            id: format!("{}-{:08x}", now.format("%Y%m%dT%H%M%S"), fastrand::u32(..)),
+            action: action,
+            issued: now,
+            expires: args.expires_in.map(|s| now + Duration::seconds(s as i64)),
+        };
+        println!("{}", directive.id);
+        list.directives
+            .push(serde_json::to_value(&directive).unwrap());
+        let response = bucket
+            .put_object(&key, &serde_json::to_vec_pretty(&list).unwrap())
+            .context("Failed to upload directives object")?;
+        if response.status_code() / 100 != 2 {
+            return Err(anyhow!(
+                "Uploading directives object failed with {}",
+                response.status_code()
+            ));
+        }
+        Ok(())
+    })
 }
 
 fn main_inner() -> Result<()> {
     let args = Args::parse();
-    let version: ExternalMeta =
+    let mut version: ExternalMeta =
         // Can't meaningfully wrap this either due to rust or serde design decisions...
-         serde_json::from_slice(&read_bytes(&args.version_meta)?)?;
+         serde_json::from_slice(&read_bytes(&args.version_meta).context(ErrorKind::Config)?)
+            .context(ErrorKind::Config)?;
+    let bucket = Bucket::new(
+        &version.internal.bucket,
+        s3::Region::from_str(&version.internal.region)
+            .context("Failed to identify s3 connection region")
+            .context(ErrorKind::Config)?,
+        Credentials::from_env()
+            .context("Failed to set up s3 credentials")
+            .context(ErrorKind::Credentials)?,
+    )
+    .context(ErrorKind::Config)?;
+    let image = match &args.image {
+        Some(i) => i,
+        None => {
+            let config = version.internal.directives.clone().ok_or_else(|| {
+                anyhow!("The version doesn't have directives configured").context(ErrorKind::Config)
+            })?;
+            return add_directive(&bucket, &config, &args);
+        }
+    };
+    version.published = Some(Utc::now());
     ec!(
         (
             "Error uploading {} to {}/{}",
-            args.image.to_string_lossy(),
+            image.to_string_lossy(),
             &version.internal.bucket,
             &version.internal.object_path
         ),
         {
-            let bucket = Bucket::new(
-                &version.internal.bucket,
-                s3::Region::from_str(&version.internal.region)
-                    .context("Failed to identify s3 connection region")?,
-                Credentials::from_env().context("Failed to set up s3 credentials")?,
-            )?;
-
             bucket
-                .put_object_stream(&mut File::open(&args.image)?, &version.internal.object_path)
+                .put_object_stream(&mut File::open(image)?, &version.internal.object_path)
                 .context("Failed to upload image")?;
             let meta_path = format!("{}.meta", version.internal.object_path);
             ec!(
@@ -58,7 +155,7 @@ fn main_inner() -> Result<()> {
 }
 
 fn main() {
-    fn main0() -> bool {
+    fn main0() -> i32 {
         let mut builder = TerminalLoggerBuilder::new();
         builder.level(Severity::Debug);
         builder.destination(Destination::Stderr);
@@ -66,15 +163,23 @@ fn main() {
         match main_inner() {
             Ok(_) => {
                 info!(root_log, "Done");
-                return true;
+                return 0;
             }
             Err(e) => {
-                err!(root_log, "Exiting with error", err = format!("{:?}", e));
-                return false;
+                let code = exit_code(&e);
+                err!(
+                    root_log,
+                    "Exiting with error",
+                    kind = ErrorKind::of(&e).map(|k| k.name()).unwrap_or("other"),
+                    code = code,
+                    err = format!("{:?}", e)
+                );
+                return code;
             }
         };
     }
-    if !main0() {
-        exit(1);
+    let code = main0();
+    if code != 0 {
+        exit(code);
     }
 }
@@ -0,0 +1,84 @@
+use std::process::{exit, Command};
+
+use anyhow::{Context, Result};
+use chrono::Duration;
+use slog::Logger;
+use sloggers::{
+    terminal::{Destination, TerminalLoggerBuilder},
+    types::Severity,
+    Build,
+};
+use tools::{
+    current_meta, ec, exit_code, grub_env_list, grub_env_set, health::watch_boot, mount_boot,
+    ErrorKind, Host, SimpleCommand, GRUB_ATTEMPTS_VAR, GRUB_NEW_VAR,
+};
+use tools::{err, info};
+
+fn main_inner(log: Logger) -> Result<()> {
+    let host = Host::system();
+    let current = current_meta()?;
+
+    // Only watch boots of a newly installed version
+    let env = ec!(("Error reading grub env"), {
+        let _mount = mount_boot(log.clone(), &host)?;
+        grub_env_list(&host)
+    })
+    .context(ErrorKind::Bootloader)?;
+    if env.get(GRUB_NEW_VAR) != Some(&current.uuid) || !env.contains_key(GRUB_ATTEMPTS_VAR) {
+        info!(log, "Not an unconfirmed update boot, nothing to watch");
+        return Ok(());
+    }
+
+    let deadline = Duration::seconds(current.watchdog_deadline as i64);
+    info!(
+        log,
+        "Watching boot of new version",
+        uuid = &current.uuid,
+        deadline = current.watchdog_deadline
+    );
+    let poll = Duration::seconds(10);
+    if !watch_boot(&log, &*host.runner, &current.health.units, deadline, poll)? {
+        return Ok(());
+    }
+
+    // With no attempts left grub boots the saved (previous) entry. The new version is left in
+    // the env so the update can tell it failed.
+    ec!(("Error rolling back"), {
+        let _mount = mount_boot(log.clone(), &host)?;
+        grub_env_set(&host, &[(GRUB_ATTEMPTS_VAR, "0")])
+    })
+    .context(ErrorKind::Bootloader)?;
+    info!(log, "Rebooting into previous version");
+    Command::new("reboot").run(&*host.runner)?;
+    Ok(())
+}
+
+fn main() {
+    fn main0() -> i32 {
+        let mut builder = TerminalLoggerBuilder::new();
+        builder.level(Severity::Debug);
+        builder.destination(Destination::Stderr);
+        let root_log = builder.build().unwrap();
+        match main_inner(root_log.clone()) {
+            Ok(_) => {
+                info!(root_log, "Done");
+                return 0;
+            }
+            Err(e) => {
+                let code = exit_code(&e);
+                err!(
+                    root_log,
+                    "Exiting with error",
+                    kind = ErrorKind::of(&e).map(|k| k.name()).unwrap_or("other"),
+                    code = code,
+                    err = format!("{:?}", e)
+                );
+                return code;
+            }
+        };
+    }
+    let code = main0();
+    if code != 0 {
+        exit(code);
+    }
+}
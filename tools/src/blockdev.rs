@@ -0,0 +1,259 @@
+// Block device discovery from sysfs, mountinfo and the gpt on each disk
+use crate::{Host, LsblkDevice};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{read_dir, read_to_string, File},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_ENTRY_NAME_LEN: usize = 72;
+
+// A gpt partition table entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct GptEntry {
+    pub type_guid: String,
+    pub name: String,
+}
+
+fn read_trimmed(path: &Path) -> Result<String> {
+    Ok(read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?
+        .trim()
+        .to_string())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+// Guids are stored with the first three fields little endian
+pub fn format_guid(b: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{}",
+        read_u32(b, 0),
+        u16::from_le_bytes([b[4], b[5]]),
+        u16::from_le_bytes([b[6], b[7]]),
+        b[8],
+        b[9],
+        b[10..16]
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<String>()
+    )
+}
+
+// Entries indexed by partition number - 1. Unused entries have a nil type guid. Returns None
+// if the disk has no gpt.
+pub fn read_gpt<R: Read + Seek>(disk: &mut R, sector_size: u64) -> Result<Option<Vec<GptEntry>>> {
+    let mut header = vec![0; sector_size as usize];
+    disk.seek(SeekFrom::Start(sector_size))?;
+    if disk.read_exact(&mut header).is_err() {
+        return Ok(None);
+    }
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    if entry_size < 56 + GPT_ENTRY_NAME_LEN || entry_count > 1024 {
+        return Err(anyhow!(
+            "Unsupported gpt layout, {} entries of {} bytes",
+            entry_count,
+            entry_size
+        ));
+    }
+    let mut raw = vec![0; entry_count * entry_size];
+    disk.seek(SeekFrom::Start(entries_lba * sector_size))?;
+    disk.read_exact(&mut raw)
+        .context("Failed to read gpt entries")?;
+    Ok(Some(
+        raw.chunks(entry_size)
+            .map(|e| {
+                let name = e[56..56 + GPT_ENTRY_NAME_LEN]
+                    .chunks(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect::<Vec<_>>();
+                GptEntry {
+                    type_guid: format_guid(&e[0..16]),
+                    name: String::from_utf16_lossy(&name),
+                }
+            })
+            .collect(),
+    ))
+}
+
+// Octal escapes like \040 for space
+fn unescape_mountinfo(s: &str) -> String {
+    let mut out = vec![];
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'\\' && i + 3 < b.len() && b[i + 1..i + 4].iter().all(|c| c.is_ascii_digit()) {
+            out.push(u8::from_str_radix(&s[i + 1..i + 4], 8).unwrap_or(b'?'));
+            i += 4;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// Mount point by device number (major:minor). If a device is mounted more than once `/` wins,
+// otherwise the first mount.
+fn read_mounts(path: &Path) -> Result<HashMap<String, String>> {
+    let mut out = HashMap::new();
+    for line in read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?
+        .lines()
+    {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        if fields.len() < 5 {
+            continue;
+        }
+        let mountpoint = unescape_mountinfo(fields[4]);
+        let existing = out
+            .entry(fields[2].to_string())
+            .or_insert(mountpoint.clone());
+        if mountpoint == "/" {
+            *existing = mountpoint;
+        }
+    }
+    Ok(out)
+}
+
+// Virtual devices lsblk doesn't list as disks
+const NON_DISK_PREFIXES: &[&str] = &["loop", "dm-", "md", "zram", "ram"];
+// scsi type of cd drives
+const SCSI_TYPE_ROM: &str = "5";
+
+// Whether `/` is mounted from the disk or one of its partitions
+fn holds_root(sys: &Path, mounts: &HashMap<String, String>) -> bool {
+    let is_root = |dir: &Path| {
+        read_trimmed(&dir.join("dev"))
+            .ok()
+            .and_then(|d| mounts.get(&d))
+            .map(|m| m == "/")
+            .unwrap_or(false)
+    };
+    if is_root(sys) {
+        return true;
+    }
+    let entries = match read_dir(sys) {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .any(|e| e.path().join("partition").exists() && is_root(&e.path()))
+}
+
+// Like lsblk's `disk` type, also leaving out removable and empty devices so an install never
+// picks a usb stick or a cd drive
+fn is_fixed_disk(sys: &Path, name: &str) -> Result<bool> {
+    if NON_DISK_PREFIXES.iter().any(|p| name.starts_with(p)) {
+        return Ok(false);
+    }
+    if read_trimmed(&sys.join("device/type")).ok().as_deref() == Some(SCSI_TYPE_ROM) {
+        return Ok(false);
+    }
+    if read_trimmed(&sys.join("removable")).ok().as_deref() == Some("1") {
+        return Ok(false);
+    }
+    Ok(device_size(sys)? > 0)
+}
+
+// Disks (and their partitions) known to the kernel, limited to `host.disk` if set. Only
+// `host.disk` can be a loop device.
+pub fn block_devices(host: &Host) -> Result<Vec<LsblkDevice>> {
+    let mounts = read_mounts(&host.mountinfo_path)?;
+    let only = host
+        .disk
+        .as_ref()
+        .map(|d| d.file_name().unwrap_or_default().to_owned());
+    let block_dir = host.sys_dir.join("block");
+    let mut names = vec![];
+    for entry in read_dir(&block_dir)
+        .with_context(|| format!("Failed to list {}", block_dir.to_string_lossy()))?
+    {
+        let name = entry?.file_name();
+        if only.is_none() || only.as_ref() == Some(&name) {
+            names.push(name.to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+
+    let mut out = vec![];
+    for name in names {
+        let sys = block_dir.join(&name);
+        // A system booted from a removable disk still updates it
+        if only.is_none() && !holds_root(&sys, &mounts) && !is_fixed_disk(&sys, &name)? {
+            continue;
+        }
+        let path = host.dev_dir.join(&name);
+        let sector_size = read_trimmed(&sys.join("queue/logical_block_size"))
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(512);
+        // Not fatal, ex: empty cd drives or permissions
+        let gpt = File::open(&path)
+            .ok()
+            .and_then(|mut f| read_gpt(&mut f, sector_size).ok().flatten())
+            .unwrap_or_default();
+        let mut children = vec![];
+        for entry in read_dir(&sys)? {
+            let part_sys = entry?.path();
+            let number = match read_trimmed(&part_sys.join("partition")) {
+                Ok(n) => n.parse::<usize>().context("Invalid partition number")?,
+                Err(_) => continue,
+            };
+            let part_name = part_sys.file_name().unwrap().to_string_lossy().to_string();
+            let entry = number.checked_sub(1).and_then(|i| gpt.get(i));
+            children.push((
+                number,
+                LsblkDevice {
+                    path: host.dev_dir.join(&part_name).to_string_lossy().to_string(),
+                    size: device_size(&part_sys)?,
+                    type_field: "part".to_string(),
+                    mountpoint: mounts.get(&read_trimmed(&part_sys.join("dev"))?).cloned(),
+                    partlabel: entry.map(|e| e.name.clone()).filter(|n| !n.is_empty()),
+                    parttype: entry.map(|e| e.type_guid.clone()),
+                    children: vec![],
+                },
+            ));
+        }
+        children.sort_by_key(|c| c.0);
+        out.push(LsblkDevice {
+            path: path.to_string_lossy().to_string(),
+            size: device_size(&sys)?,
+            type_field: if name.starts_with("loop") {
+                "loop"
+            } else {
+                "disk"
+            }
+            .to_string(),
+            mountpoint: mounts.get(&read_trimmed(&sys.join("dev"))?).cloned(),
+            partlabel: None,
+            parttype: None,
+            children: children.into_iter().map(|c| c.1).collect(),
+        });
+    }
+    Ok(out)
+}
+
+// Sysfs sizes are always in 512 byte sectors
+fn device_size(sys: &Path) -> Result<i64> {
+    Ok(read_trimmed(&sys.join("size"))?
+        .parse::<i64>()
+        .context("Invalid device size")?
+        * 512)
+}
@@ -0,0 +1,185 @@
+// Checks that the system clock is plausible before signing requests. Devices with dead rtc
+// batteries boot in 1970 and every signature is rejected until ntp catches up.
+use crate::{warn, InternalMeta};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    path::Path,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+// Used as the floor if the version doesn't have a build time (2026-01-01)
+const MIN_PLAUSIBLE_TIME: i64 = 1767225600;
+// Created by systemd-timesyncd once the clock has been synchronized
+pub const TIMESYNC_FLAG_PATH: &'static str = "/run/systemd/timesync/synchronized";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockFix {
+    // Keep waiting until ntp sets the clock
+    #[default]
+    Wait,
+    // Sign requests with the time from the update server's `Date` header
+    ServerDate,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ClockConfig {
+    // What to do if the clock is before the version build time
+    #[serde(default)]
+    pub fix: ClockFix,
+    // Also wait until systemd-timesyncd reports the clock synchronized
+    #[serde(default)]
+    pub require_sync: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClockState {
+    Ok,
+    // Before the floor
+    Implausible,
+    // Plausible but not yet synchronized, and sync is required
+    Unsynced,
+}
+
+// Any earlier time must be wrong
+pub fn time_floor(meta: &InternalMeta) -> DateTime<Utc> {
+    Utc.timestamp_opt(meta.build_time.unwrap_or(MIN_PLAUSIBLE_TIME), 0)
+        .unwrap()
+}
+
+pub fn clock_state(
+    config: &ClockConfig,
+    floor: DateTime<Utc>,
+    now: DateTime<Utc>,
+    sync_flag: &Path,
+) -> ClockState {
+    if now < floor {
+        return ClockState::Implausible;
+    }
+    if config.require_sync && !sync_flag.exists() {
+        return ClockState::Unsynced;
+    }
+    ClockState::Ok
+}
+
+// The server's clock as an offset from the system clock, dropped once the system clock reaches
+// the floor (ex: ntp caught up) so a stale offset never outlives the problem it works around
+struct ServerClock {
+    // Seconds the system clock is behind the server, 0 if unused
+    offset: AtomicI64,
+    floor: AtomicI64,
+}
+
+impl ServerClock {
+    const fn new() -> ServerClock {
+        ServerClock {
+            offset: AtomicI64::new(0),
+            floor: AtomicI64::new(0),
+        }
+    }
+
+    // Returns true if the server's clock wasn't already in use
+    fn set(&self, system: DateTime<Utc>, server: DateTime<Utc>, floor: DateTime<Utc>) -> bool {
+        self.floor.store(floor.timestamp(), Ordering::Relaxed);
+        self.offset
+            .swap((server - system).num_seconds(), Ordering::Relaxed)
+            == 0
+    }
+
+    fn reset(&self) {
+        self.offset.store(0, Ordering::Relaxed);
+    }
+
+    fn now(&self, system: DateTime<Utc>) -> DateTime<Utc> {
+        let offset = self.offset.load(Ordering::Relaxed);
+        if offset == 0 {
+            return system;
+        }
+        if system.timestamp() >= self.floor.load(Ordering::Relaxed) {
+            let _ = self
+                .offset
+                .compare_exchange(offset, 0, Ordering::Relaxed, Ordering::Relaxed);
+            return system;
+        }
+        system + Duration::seconds(offset)
+    }
+}
+
+static SERVER_CLOCK: ServerClock = ServerClock::new();
+
+// Signs requests with the server's clock until the system clock reaches `floor`. The system
+// clock itself is left to ntp, the `Date` header isn't authenticated.
+pub fn use_server_date(log: &Logger, server: DateTime<Utc>, floor: DateTime<Utc>) {
+    if SERVER_CLOCK.set(Utc::now(), server, floor) {
+        warn!(
+            log,
+            "Signing requests with the server's clock",
+            system = Utc::now().to_rfc3339(),
+            server = server.to_rfc3339()
+        );
+    }
+}
+
+pub fn use_system_clock() {
+    SERVER_CLOCK.reset();
+}
+
+// The time to sign requests and compare with other devices' times at
+pub fn now() -> DateTime<Utc> {
+    SERVER_CLOCK.now(Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::meta;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    #[test]
+    fn clock_before_build_time_is_implausible() {
+        let dir = TempDir::new().unwrap();
+        let flag = dir.path().join("synchronized");
+        let mut meta = meta("first");
+        meta.build_time = Some(1_800_000_000);
+        let floor = time_floor(&meta);
+        let epoch = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let later = floor + Duration::days(1);
+        let mut config = ClockConfig::default();
+        assert_eq!(
+            clock_state(&config, floor, epoch, &flag),
+            ClockState::Implausible
+        );
+        assert_eq!(clock_state(&config, floor, later, &flag), ClockState::Ok);
+        config.require_sync = true;
+        assert_eq!(
+            clock_state(&config, floor, later, &flag),
+            ClockState::Unsynced
+        );
+        File::create(&flag).unwrap();
+        assert_eq!(clock_state(&config, floor, later, &flag), ClockState::Ok);
+    }
+
+    #[test]
+    fn server_offset_is_dropped_once_system_clock_is_plausible() {
+        let at = |t| Utc.timestamp_opt(t, 0).unwrap();
+        let floor = at(1_800_000_000);
+        let clock = ServerClock::new();
+        assert_eq!(clock.now(at(1000)), at(1000));
+
+        assert!(clock.set(at(1000), floor + Duration::hours(1), floor));
+        assert!(!clock.set(at(1000), floor + Duration::hours(1), floor));
+        assert_eq!(clock.now(at(1060)), floor + Duration::minutes(61));
+
+        // Ntp set the clock
+        let fixed = floor + Duration::hours(2);
+        assert_eq!(clock.now(fixed), fixed);
+        // Even if it's set back before the floor afterwards
+        assert_eq!(clock.now(at(1060)), at(1060));
+        assert!(clock.set(at(1000), floor, floor));
+        clock.reset();
+        assert_eq!(clock.now(at(1060)), at(1060));
+    }
+}
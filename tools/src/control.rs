@@ -0,0 +1,489 @@
+// Local control socket so applications (ex: a kiosk UI) can show update progress and decide
+// when to reboot. Each connection sends newline-delimited json requests and gets one json line
+// back per request. After `subscribe` the connection also gets a line for every status change.
+use crate::{ec, info, warn};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    ffi::CString,
+    fs::{create_dir_all, remove_file, set_permissions, Permissions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+fn default_control_path() -> PathBuf {
+    PathBuf::from("/run/organixm/control.sock")
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ControlConfig {
+    #[serde(default = "default_control_path")]
+    pub path: PathBuf,
+    // Group allowed to use the socket besides root
+    #[serde(default)]
+    pub group: Option<String>,
+    // Don't reboot into a new version until a client approves (or schedules it by postponing)
+    #[serde(default)]
+    pub require_approval: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    #[default]
+    Idle,
+    Checking,
+    Downloading,
+    Verifying,
+    // Written and verified, waiting to be activated
+    Staged,
+    // Waiting for approval or the scheduled reboot time
+    AwaitingReboot,
+    WaitingForLease,
+    Activating,
+    Rebooting,
+    // The last run failed, see `error`
+    Failed,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Status {
+    pub phase: Phase,
+    // Booted version
+    pub current: String,
+    // Version being installed
+    pub new: Option<String>,
+    // Image bytes written so far and in total while downloading
+    pub downloaded: Option<u64>,
+    pub size: Option<u64>,
+    // When a pending reboot will happen, if scheduled
+    pub reboot_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+impl Status {
+    // One line for people, ex: systemd's unit status
+    pub fn describe(&self) -> String {
+        let new = self.new.as_deref().unwrap_or("new version");
+        match self.phase {
+            Phase::Idle => "Idle".to_string(),
+            Phase::Checking => "Checking for a new version".to_string(),
+            Phase::Downloading => match (self.downloaded, self.size) {
+                (Some(d), Some(s)) if s > 0 => {
+                    format!("Downloading {} {}% ({}/{} bytes)", new, d * 100 / s, d, s)
+                }
+                _ => format!("Downloading {}", new),
+            },
+            Phase::Verifying => format!("Verifying {}", new),
+            Phase::Staged => format!("Staged {}", new),
+            Phase::AwaitingReboot => match self.reboot_at {
+                Some(at) => format!("Rebooting into {} at {}", new, at.to_rfc3339()),
+                None => format!("Waiting for approval to reboot into {}", new),
+            },
+            Phase::WaitingForLease => format!("Waiting for a reboot lease for {}", new),
+            Phase::Activating => format!("Activating {}", new),
+            Phase::Rebooting => format!("Rebooting into {}", new),
+            Phase::Failed => format!(
+                "Failed: {}",
+                self.error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    // Check for a new version now
+    Check,
+    // Reboot into the pending version now, or activate the staged version
+    Approve,
+    // Reboot into the pending version in `seconds` instead
+    Postpone { seconds: u64 },
+    // Get status changes as events on this connection
+    Subscribe,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Event {
+    // Always "status" for now
+    pub event: String,
+    pub status: Status,
+}
+
+// What the serving loop should do next
+#[derive(Debug, PartialEq)]
+pub enum Wake {
+    Check,
+    Activate,
+    // Nothing was asked before the deadline
+    Timeout,
+}
+
+#[derive(Default)]
+struct ControlState {
+    status: Status,
+    check_requested: bool,
+    approved: bool,
+    // The pending reboot can't happen before the maintenance window opens, even if approved
+    not_before: Option<DateTime<Utc>>,
+}
+
+pub struct Control {
+    log: Logger,
+    pub config: ControlConfig,
+    state: Mutex<ControlState>,
+    changed: Condvar,
+    subscribers: Mutex<Vec<UnixStream>>,
+}
+
+fn chown_group(path: &Path, group: &str) -> Result<()> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow!("No group named {}", group));
+    }
+    let gid = unsafe { (*entry).gr_gid };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(c_path.as_ptr(), u32::MAX, gid) } != 0 {
+        return Err(io::Error::last_os_error()).context("chown failed");
+    }
+    Ok(())
+}
+
+impl Control {
+    pub fn new(log: Logger, config: ControlConfig, current: &str) -> Arc<Control> {
+        Arc::new(Control {
+            log: log,
+            config: config,
+            state: Mutex::new(ControlState {
+                status: Status {
+                    current: current.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+            subscribers: Mutex::new(vec![]),
+        })
+    }
+
+    // Binds the socket and serves clients in the background
+    pub fn listen(self: &Arc<Self>) -> Result<()> {
+        let path = self.config.path.clone();
+        let listener = ec!(
+            ("Error creating control socket {}", path.to_string_lossy()),
+            {
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)?;
+                }
+                // Left over from a previous run
+                if path.exists() {
+                    remove_file(&path)?;
+                }
+                let listener = UnixListener::bind(&path)?;
+                let mode = if self.config.group.is_some() {
+                    0o660
+                } else {
+                    0o600
+                };
+                set_permissions(&path, Permissions::from_mode(mode))?;
+                if let Some(group) = &self.config.group {
+                    chown_group(&path, group)?;
+                }
+                Ok(listener)
+            }
+        )?;
+        info!(
+            self.log,
+            "Listening on control socket",
+            path = path.to_string_lossy().to_string()
+        );
+        let control = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let control = control.clone();
+                        thread::spawn(move || control.serve_client(stream));
+                    }
+                    Err(e) => warn!(
+                        control.log,
+                        "Control socket accept failed",
+                        err = format!("{:?}", e)
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn serve_client(&self, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line)
+                .context("Invalid request")
+                .and_then(|req| self.request(req, &writer))
+            {
+                Ok(status) => Response {
+                    ok: true,
+                    status: Some(status),
+                    error: None,
+                },
+                Err(e) => Response {
+                    ok: false,
+                    status: None,
+                    error: Some(format!("{:#}", e)),
+                },
+            };
+            let mut out = serde_json::to_vec(&response).unwrap();
+            out.push(b'\n');
+            if writer.write_all(&out).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Handles one request, returning the status afterwards
+    pub fn request(&self, req: Request, client: &UnixStream) -> Result<Status> {
+        match req {
+            Request::Status => {}
+            Request::Check => {
+                self.state.lock().unwrap().check_requested = true;
+                self.changed.notify_all();
+            }
+            Request::Approve => {
+                let mut state = self.state.lock().unwrap();
+                match state.status.phase {
+                    Phase::AwaitingReboot | Phase::Staged => {
+                        info!(self.log, "Reboot approved by control client");
+                        state.approved = true;
+                    }
+                    _ => return Err(anyhow!("No update is waiting to be activated")),
+                }
+                drop(state);
+                self.changed.notify_all();
+            }
+            Request::Postpone { seconds } => {
+                if self.status().phase != Phase::AwaitingReboot {
+                    return Err(anyhow!("No reboot is pending"));
+                }
+                let mut at = Utc::now() + Duration::seconds(seconds as i64);
+                if let Some(not_before) = self.state.lock().unwrap().not_before {
+                    at = at.max(not_before);
+                }
+                info!(
+                    self.log,
+                    "Reboot postponed by control client",
+                    until = at.to_rfc3339()
+                );
+                self.update(|s| s.reboot_at = Some(at));
+            }
+            Request::Subscribe => {
+                let subscriber = client.try_clone()?;
+                // A stalled client is dropped rather than blocking the updater
+                subscriber.set_write_timeout(Some(std::time::Duration::from_secs(1)))?;
+                self.subscribers.lock().unwrap().push(subscriber);
+            }
+        }
+        Ok(self.status())
+    }
+
+    pub fn status(&self) -> Status {
+        self.state.lock().unwrap().status.clone()
+    }
+
+    // Changes the status and tells subscribers if anything changed
+    pub fn update<F: FnOnce(&mut Status)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        let before = state.status.clone();
+        f(&mut state.status);
+        if state.status == before {
+            return;
+        }
+        // An approval only applies to the version it was given for
+        if !matches!(state.status.phase, Phase::Staged | Phase::AwaitingReboot) {
+            state.approved = false;
+        }
+        let mut line = serde_json::to_vec(&Event {
+            event: "status".to_string(),
+            status: state.status.clone(),
+        })
+        .unwrap();
+        line.push(b'\n');
+        drop(state);
+        self.changed.notify_all();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|s| s.write_all(&line).is_ok());
+    }
+
+    // Blocks until a client asks for a check or approves activating the staged version, or
+    // `until` passes
+    pub fn wait(&self, until: Option<DateTime<Utc>>) -> Wake {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.check_requested {
+                state.check_requested = false;
+                return Wake::Check;
+            }
+            if state.approved && state.status.phase == Phase::Staged {
+                return Wake::Activate;
+            }
+            let wait = match until {
+                Some(u) if u <= Utc::now() => return Wake::Timeout,
+                Some(u) => (u - Utc::now()).to_std().unwrap_or_default(),
+                None => std::time::Duration::from_secs(3600),
+            };
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    // When a reboot into a new version becomes due, unless a client has to approve it. Never
+    // before `not_before`, which also holds back approvals and postpones until `await_reboot`
+    // returns.
+    pub fn reboot_at(
+        &self,
+        delay: Duration,
+        not_before: Option<DateTime<Utc>>,
+    ) -> Option<DateTime<Utc>> {
+        self.state.lock().unwrap().not_before = not_before;
+        if self.config.require_approval {
+            return None;
+        }
+        let at = Utc::now() + delay;
+        return Some(not_before.map(|n| at.max(n)).unwrap_or(at));
+    }
+
+    // Blocks until the pending reboot is approved or its scheduled time (moved by postponing)
+    // comes, but not before the `not_before` given to `reboot_at`
+    pub fn await_reboot(&self) {
+        info!(
+            self.log,
+            "Waiting to reboot",
+            require_approval = self.config.require_approval
+        );
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let due = match (state.approved, state.status.reboot_at) {
+                (true, _) => Some(state.not_before.unwrap_or_else(Utc::now)),
+                (false, at) => at,
+            };
+            let wait = match due {
+                Some(at) if at <= Utc::now() => break,
+                Some(at) => (at - Utc::now()).to_std().unwrap_or_default(),
+                None => std::time::Duration::from_secs(3600),
+            };
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+        state.approved = false;
+        state.not_before = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::logger;
+    use std::time::Instant;
+
+    fn control(require_approval: bool) -> Arc<Control> {
+        let config = ControlConfig {
+            path: PathBuf::from("/nonexistent/control.sock"),
+            group: None,
+            require_approval: require_approval,
+        };
+        let control = Control::new(logger(), config, "current");
+        control.update(|s| s.phase = Phase::AwaitingReboot);
+        control
+    }
+
+    #[test]
+    fn reboot_at_is_clamped_to_not_before() {
+        let control = control(false);
+        let approving = self::control(true);
+        let opens = Utc::now() + Duration::hours(2);
+        assert_eq!(
+            control.reboot_at(Duration::seconds(15), Some(opens)),
+            Some(opens)
+        );
+        assert!(control.reboot_at(Duration::seconds(15), None).unwrap() < opens);
+        assert_eq!(
+            approving.reboot_at(Duration::seconds(15), Some(opens)),
+            None
+        );
+    }
+
+    #[test]
+    fn approval_waits_for_not_before() {
+        let control = control(true);
+        let opens = Utc::now() + Duration::milliseconds(300);
+        let reboot_at = control.reboot_at(Duration::zero(), Some(opens));
+        control.update(|s| s.reboot_at = reboot_at);
+        let (client, _) = UnixStream::pair().unwrap();
+        control.request(Request::Approve, &client).unwrap();
+        let start = Instant::now();
+        control.await_reboot();
+        assert!(Utc::now() >= opens);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn approval_without_not_before_reboots_at_once() {
+        let control = control(true);
+        let reboot_at = control.reboot_at(Duration::zero(), None);
+        control.update(|s| s.reboot_at = reboot_at);
+        let (client, _) = UnixStream::pair().unwrap();
+        control.request(Request::Approve, &client).unwrap();
+        let start = Instant::now();
+        control.await_reboot();
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn postpone_is_clamped_to_not_before() {
+        let control = control(false);
+        let opens = Utc::now() + Duration::hours(2);
+        let reboot_at = control.reboot_at(Duration::zero(), Some(opens));
+        control.update(|s| s.reboot_at = reboot_at);
+        let (client, _) = UnixStream::pair().unwrap();
+        let status = control
+            .request(Request::Postpone { seconds: 60 }, &client)
+            .unwrap();
+        assert_eq!(status.reboot_at, Some(opens));
+        let status = control
+            .request(Request::Postpone { seconds: 3 * 3600 }, &client)
+            .unwrap();
+        assert!(status.reboot_at.unwrap() > opens);
+    }
+}
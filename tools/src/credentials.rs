@@ -0,0 +1,331 @@
+// Credentials for pulling new versions, from the version meta, an instance metadata endpoint
+// or a rotatable file on the rw partition
+use crate::{ec, http::HttpClient, InternalMeta, RW_STATE_DIR};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use s3::creds::Credentials;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const CREDENTIALS_FILE: &'static str = "credentials.json";
+const IMDS_TOKEN_TTL_SECS: u32 = 21600;
+const IMDS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Same shape as instance metadata credentials and `aws sts assume-role` output, so either can
+// be written to the credentials file directly
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct KeyCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // Sts session token
+    #[serde(default, alias = "Token")]
+    pub session_token: Option<String>,
+    #[serde(default)]
+    pub expiration: Option<DateTime<Utc>>,
+}
+
+fn default_imds_endpoint() -> String {
+    "http://169.254.169.254".to_string()
+}
+
+fn default_credentials_path() -> PathBuf {
+    Path::new(RW_STATE_DIR).join(CREDENTIALS_FILE)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CredentialSource {
+    // Keys in the version meta
+    Static {
+        access_key: String,
+        secret_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+    // Role credentials from an EC2 style instance metadata service (IMDSv2, falling back to v1)
+    InstanceMetadata {
+        #[serde(default = "default_imds_endpoint")]
+        endpoint: String,
+        // Defaults to the first role listed by the endpoint
+        #[serde(default)]
+        role: Option<String>,
+    },
+    // `KeyCredentials` json, re-read whenever the previous credentials expire (or on every
+    // request if they don't say) so it can be rotated without a new version
+    File {
+        #[serde(default = "default_credentials_path")]
+        path: PathBuf,
+    },
+}
+
+impl CredentialSource {
+    // The configured source, or the keys in the meta for versions from before sources
+    pub fn of(meta: &InternalMeta) -> CredentialSource {
+        match &meta.credentials {
+            Some(c) => c.clone(),
+            None => CredentialSource::Static {
+                access_key: meta.access_key.clone(),
+                secret_key: meta.secret_key.clone(),
+                session_token: None,
+            },
+        }
+    }
+}
+
+fn imds_get(http: &HttpClient, url: &str, token: &Option<String>) -> Result<String> {
+    let headers = match token {
+        Some(token) => vec![("X-aws-ec2-metadata-token", token.as_str())],
+        None => vec![],
+    };
+    let (status, _, body) = http.send("GET", url, &headers, &[])?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("{} responded with status {}", url, status));
+    }
+    String::from_utf8(body).with_context(|| format!("Invalid response from {}", url))
+}
+
+fn fetch_instance_metadata(
+    http: &HttpClient,
+    endpoint: &str,
+    role: &Option<String>,
+) -> Result<KeyCredentials> {
+    ec!(("Error getting credentials from {}", endpoint), {
+        let token = http
+            .send(
+                "PUT",
+                &format!("{}/latest/api/token", endpoint),
+                &[(
+                    "X-aws-ec2-metadata-token-ttl-seconds",
+                    &IMDS_TOKEN_TTL_SECS.to_string(),
+                )],
+                &[],
+            )
+            .ok()
+            .filter(|(status, _, _)| (200..300).contains(status))
+            .and_then(|(_, _, body)| String::from_utf8(body).ok());
+        let base = format!("{}/latest/meta-data/iam/security-credentials/", endpoint);
+        let role = match role {
+            Some(r) => r.clone(),
+            None => imds_get(http, &base, &token)?
+                .lines()
+                .next()
+                .ok_or_else(|| anyhow!("Instance has no role"))?
+                .to_string(),
+        };
+        Ok(
+            serde_json::from_str(&imds_get(http, &format!("{}{}", base, role), &token)?)
+                .context("Failed to parse role credentials")?,
+        )
+    })
+}
+
+fn read_file(path: &Path) -> Result<KeyCredentials> {
+    ec!(
+        ("Error reading credentials from {}", path.to_string_lossy()),
+        { Ok(serde_json::from_slice(&std::fs::read(path)?)?) }
+    )
+}
+
+// Writes credentials only readable by root
+pub fn write_credentials_file(path: &Path, creds: &KeyCredentials) -> Result<()> {
+    ec!(
+        ("Error writing credentials to {}", path.to_string_lossy()),
+        {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)?;
+            // Mode only applies to new files
+            file.set_permissions(Permissions::from_mode(0o600))?;
+            file.write_all(&serde_json::to_vec_pretty(creds)?)?;
+            Ok(())
+        }
+    )
+}
+
+pub struct CredentialProvider {
+    source: CredentialSource,
+    // For instance metadata
+    http: HttpClient,
+    cached: Mutex<Option<KeyCredentials>>,
+}
+
+impl CredentialProvider {
+    pub fn new(source: CredentialSource, http: &HttpClient) -> CredentialProvider {
+        CredentialProvider {
+            source: source,
+            http: http.clone().with_timeout(IMDS_TIMEOUT),
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Result<Credentials> {
+        let mut cached = self.cached.lock().unwrap();
+        let fresh = |c: &KeyCredentials| match c.expiration {
+            Some(e) => e > Utc::now() + Duration::minutes(5),
+            None => false,
+        };
+        let creds = match cached.as_ref().filter(|c| fresh(c)) {
+            Some(c) => c.clone(),
+            None => {
+                let c = match &self.source {
+                    CredentialSource::Static {
+                        access_key,
+                        secret_key,
+                        session_token,
+                    } => KeyCredentials {
+                        access_key_id: access_key.clone(),
+                        secret_access_key: secret_key.clone(),
+                        session_token: session_token.clone(),
+                        expiration: None,
+                    },
+                    CredentialSource::InstanceMetadata { endpoint, role } => {
+                        fetch_instance_metadata(&self.http, endpoint, role)?
+                    }
+                    CredentialSource::File { path } => read_file(path)?,
+                };
+                if let Some(e) = c.expiration {
+                    if e <= Utc::now() {
+                        return Err(anyhow!("Credentials expired at {}", e));
+                    }
+                }
+                *cached = Some(c.clone());
+                c
+            }
+        };
+        Ok(Credentials {
+            access_key: Some(creds.access_key_id),
+            secret_key: Some(creds.secret_access_key),
+            security_token: None,
+            session_token: creds.session_token,
+            expiration: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+    use tempfile::TempDir;
+
+    fn http() -> HttpClient {
+        HttpClient::new(&Default::default()).unwrap()
+    }
+
+    #[test]
+    fn file_credentials_are_reread_after_rotation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("organixm/credentials.json");
+        let provider =
+            CredentialProvider::new(CredentialSource::File { path: path.clone() }, &http());
+        assert!(provider.get().is_err());
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            r#"{"AccessKeyId": "AKIA1", "SecretAccessKey": "secret1", "SessionToken": "token1"}"#,
+        )
+        .unwrap();
+        let creds = provider.get().unwrap();
+        assert_eq!(creds.access_key.as_deref(), Some("AKIA1"));
+        assert_eq!(creds.session_token.as_deref(), Some("token1"));
+
+        write_credentials_file(
+            &path,
+            &KeyCredentials {
+                access_key_id: "AKIA2".to_string(),
+                secret_access_key: "secret2".to_string(),
+                session_token: None,
+                expiration: Some(chrono::Utc::now() + Duration::hours(1)),
+            },
+        )
+        .unwrap();
+        assert_eq!(provider.get().unwrap().access_key.as_deref(), Some("AKIA2"));
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        write_credentials_file(
+            &path,
+            &KeyCredentials {
+                access_key_id: "AKIA3".to_string(),
+                secret_access_key: "secret3".to_string(),
+                session_token: None,
+                expiration: Some(chrono::Utc::now() - Duration::hours(1)),
+            },
+        )
+        .unwrap();
+        // Still fresh, not re-read
+        assert_eq!(provider.get().unwrap().access_key.as_deref(), Some("AKIA2"));
+        let expired = CredentialProvider::new(CredentialSource::File { path: path }, &http());
+        assert!(format!("{:?}", expired.get().unwrap_err()).contains("expired"));
+    }
+
+    #[test]
+    fn instance_metadata_credentials_use_session_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut heads = vec![];
+            for body in [
+                "imds-token",
+                "kiosk-role\n",
+                r#"{"Code": "Success", "AccessKeyId": "ASIA1", "SecretAccessKey": "secret", "Token": "session", "Expiration": "2999-01-01T00:00:00Z"}"#,
+            ] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut r = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    r.read_line(&mut head).unwrap();
+                }
+                write!(
+                    r.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                heads.push(head);
+            }
+            heads
+        });
+        let provider = CredentialProvider::new(
+            CredentialSource::InstanceMetadata {
+                endpoint: endpoint,
+                role: None,
+            },
+            &http(),
+        );
+        let creds = provider.get().unwrap();
+        assert_eq!(creds.access_key.as_deref(), Some("ASIA1"));
+        assert_eq!(creds.session_token.as_deref(), Some("session"));
+        let heads = server.join().unwrap();
+        assert!(heads[0].starts_with("PUT /latest/api/token "));
+        assert!(heads[1].starts_with("GET /latest/meta-data/iam/security-credentials/ "));
+        assert!(heads[2].starts_with("GET /latest/meta-data/iam/security-credentials/kiosk-role "));
+        assert!(heads[2]
+            .to_ascii_lowercase()
+            .contains("x-aws-ec2-metadata-token: imds-token"));
+        // Cached until close to expiry
+        assert_eq!(provider.get().unwrap().access_key.as_deref(), Some("ASIA1"));
+    }
+}
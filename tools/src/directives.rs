@@ -0,0 +1,552 @@
+// Remote per-device directives. An operator writes `<prefix>/<device id>/directives.json` in the
+// version bucket (`upload --directive`), the updater runs directives it hasn't run before from a
+// fixed set of actions and acknowledges each with `<prefix>/<device id>/results/<id>.json`.
+// Directives aren't signed. Presigned requests only prove which credentials fetched them, so
+// they're trusted because only writers of the prefix can publish them. Devices write their
+// results there too, so they get their own credentials, which should be scoped to the device's
+// own keys.
+use crate::{
+    check_s3_status, clock,
+    credentials::{CredentialProvider, CredentialSource},
+    ec,
+    http::HttpClient,
+    info,
+    lease::device_id,
+    presign_url, read_bytes,
+    runner::Runner,
+    warn, ErrorKind, InternalMeta,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    fs::{create_dir_all, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+const PRESIGN_EXPIRY_SECS: u32 = 300;
+// Created in the state dir, /var's overlay is wiped at the next boot if it exists
+pub const RESET_VAR_FILE: &'static str = "reset-var";
+// Executed directive ids remembered, older ones must have left the directives object
+const EXECUTED_KEPT: usize = 200;
+const DEFAULT_LOG_LINES: u32 = 10000;
+
+fn default_directives_prefix() -> String {
+    "devices".to_string()
+}
+
+fn default_directives_interval() -> u64 {
+    300
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DirectivesConfig {
+    // Key prefix in the version bucket, directives are `<prefix>/<device id>/directives.json`
+    #[serde(default = "default_directives_prefix")]
+    pub prefix: String,
+    // Seconds between polls while the updater keeps running (`update --serve`)
+    #[serde(default = "default_directives_interval")]
+    pub interval: u64,
+    // Must be able to get `<prefix>/<device id>/directives.json` and put under
+    // `<prefix>/<device id>/results/` and `logs/`. Required rather than defaulting to the
+    // version's, with which any device could write every other device's directives.
+    pub credentials: CredentialSource,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Reboot,
+    // Check for a new version now
+    Check,
+    // Wipe /var at the next boot, then reboot
+    ResetVar,
+    // Upload the current boot's journal, the last `lines` lines
+    UploadLogs {
+        #[serde(default)]
+        lines: Option<u32>,
+    },
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Reboot => "reboot",
+            Action::Check => "check",
+            Action::ResetVar => "reset_var",
+            Action::UploadLogs { .. } => "upload_logs",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Directive {
+    // Unique, a directive is only run once per device
+    pub id: String,
+    #[serde(flatten)]
+    pub action: Action,
+    pub issued: DateTime<Utc>,
+    // Not run after this
+    #[serde(default)]
+    pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DirectiveList {
+    // Must be the device reading it
+    pub device: String,
+    // Parsed one at a time so unknown actions are rejected individually
+    pub directives: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Done,
+    Failed,
+    // Not a known action or malformed
+    Rejected,
+    Expired,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DirectiveResult {
+    pub id: String,
+    pub device: String,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub error: Option<String>,
+    // Key of anything uploaded, ex: logs
+    #[serde(default)]
+    pub object: Option<String>,
+    pub finished: DateTime<Utc>,
+}
+
+// Directive ids already run, so directives left in the bucket aren't run again
+#[derive(Default, Deserialize, Serialize)]
+pub struct DirectiveState {
+    pub executed: Vec<String>,
+}
+
+fn directive_state_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("directives.json")
+}
+
+pub fn read_directive_state(state_dir: &Path) -> Result<DirectiveState> {
+    let path = directive_state_path(state_dir);
+    if !path.exists() {
+        return Ok(DirectiveState::default());
+    }
+    Ok(serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse directive state")?)
+}
+
+fn write_directive_state(state_dir: &Path, state: &DirectiveState) -> Result<()> {
+    let path = directive_state_path(state_dir);
+    ec!(("Writing directive state to {}", path.to_string_lossy()), {
+        create_dir_all(state_dir).context("Failed to create state dir")?;
+        File::create(&path)
+            .context("Failed to open for writing")?
+            .write_all(&serde_json::to_vec_pretty(state).unwrap())
+            .context("Failed to write")?;
+        Ok(())
+    })
+}
+
+// Where directives and results are kept, the version bucket in practice
+pub trait DirectiveStore {
+    // None if the object doesn't exist
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, body: &[u8], content_type: &str) -> Result<()>;
+}
+
+impl<T: DirectiveStore + ?Sized> DirectiveStore for Arc<T> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn put(&self, key: &str, body: &[u8], content_type: &str) -> Result<()> {
+        (**self).put(key, body, content_type)
+    }
+}
+
+pub struct S3DirectiveStore {
+    bucket: InternalMeta,
+    credentials: CredentialProvider,
+    http: HttpClient,
+}
+
+impl S3DirectiveStore {
+    pub fn new(current: &InternalMeta, config: &DirectivesConfig) -> Result<S3DirectiveStore> {
+        let http = HttpClient::new(&current.http).context(ErrorKind::Config)?;
+        Ok(S3DirectiveStore {
+            bucket: current.clone(),
+            credentials: CredentialProvider::new(config.credentials.clone(), &http),
+            http: http,
+        })
+    }
+
+    fn presign(&self, method: &str, key: &str) -> Result<String> {
+        let credentials = self.credentials.get().context(ErrorKind::Credentials)?;
+        presign_url(&self.bucket, credentials, method, key, PRESIGN_EXPIRY_SECS)
+    }
+}
+
+impl DirectiveStore for S3DirectiveStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let url = self.presign("GET", key)?;
+        let (status, _, body) = self
+            .http
+            .send("GET", &url, &[], &[])
+            .context(ErrorKind::Network)?;
+        if status == 404 {
+            return Ok(None);
+        }
+        check_s3_status(status)?;
+        Ok(Some(body))
+    }
+
+    fn put(&self, key: &str, body: &[u8], content_type: &str) -> Result<()> {
+        let url = self.presign("PUT", key)?;
+        let (status, _, _) = self
+            .http
+            .send("PUT", &url, &[("Content-Type", content_type)], body)
+            .context(ErrorKind::Network)?;
+        check_s3_status(status)
+    }
+}
+
+// Objects kept in memory, for tests
+#[cfg(any(test, feature = "testing"))]
+#[derive(Default)]
+pub struct MemoryDirectiveStore {
+    objects: std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MemoryDirectiveStore {
+    // Writes `directives` for `device` as an operator would
+    pub fn publish(&self, device: &str, directives: serde_json::Value) {
+        let list = serde_json::json!({ "device": device, "directives": directives });
+        self.put(
+            &format!("devices/{}/directives.json", device),
+            &serde_json::to_vec(&list).unwrap(),
+            "application/json",
+        )
+        .unwrap();
+    }
+
+    pub fn result(&self, device: &str, id: &str) -> Option<DirectiveResult> {
+        self.get(&format!("devices/{}/results/{}.json", device, id))
+            .unwrap()
+            .map(|r| serde_json::from_slice(&r).unwrap())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl DirectiveStore for MemoryDirectiveStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, body: &[u8], _content_type: &str) -> Result<()> {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), body.to_vec());
+        Ok(())
+    }
+}
+
+// What running directives asks of the caller
+#[derive(Debug, Default, PartialEq)]
+pub struct Requested {
+    pub check: bool,
+    pub reboot: bool,
+}
+
+pub struct Directives {
+    log: Logger,
+    pub config: DirectivesConfig,
+    // This device
+    device: String,
+    state_dir: PathBuf,
+    store: Box<dyn DirectiveStore>,
+    runner: Arc<dyn Runner>,
+}
+
+impl Directives {
+    pub fn new(
+        log: Logger,
+        config: DirectivesConfig,
+        device: String,
+        state_dir: PathBuf,
+        store: Box<dyn DirectiveStore>,
+        runner: Arc<dyn Runner>,
+    ) -> Directives {
+        Directives {
+            log: log,
+            config: config,
+            device: device,
+            state_dir: state_dir,
+            store: store,
+            runner: runner,
+        }
+    }
+
+    // For the running system, if the version has directives configured
+    pub fn system(
+        log: Logger,
+        current: &InternalMeta,
+        state_dir: &Path,
+        runner: Arc<dyn Runner>,
+    ) -> Result<Option<Directives>> {
+        let config = match &current.directives {
+            Some(c) => c.clone(),
+            None => return Ok(None),
+        };
+        let store = S3DirectiveStore::new(current, &config)?;
+        Ok(Some(Directives::new(
+            log,
+            config,
+            device_id(state_dir).context(ErrorKind::Disk)?,
+            state_dir.to_path_buf(),
+            Box::new(store),
+            runner,
+        )))
+    }
+
+    pub fn key(&self, name: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.config.prefix.trim_end_matches('/'),
+            self.device,
+            name
+        )
+    }
+
+    // Runs directives that weren't run before and acknowledges them. Reboots and checks are left
+    // to the caller.
+    pub fn run(&self) -> Result<Requested> {
+        let mut requested = Requested::default();
+        let key = self.key("directives.json");
+        let body = match self.store.get(&key)? {
+            Some(b) => b,
+            None => return Ok(requested),
+        };
+        let list: DirectiveList = serde_json::from_slice(&body)
+            .with_context(|| format!("Invalid directives object {}", key))
+            .context(ErrorKind::Server)?;
+        if list.device != self.device {
+            return Err(
+                anyhow!("Directives object {} is for device {}", key, list.device)
+                    .context(ErrorKind::Server),
+            );
+        }
+        let mut state = read_directive_state(&self.state_dir).context(ErrorKind::Disk)?;
+        for value in list.directives {
+            let id = match value.get("id").and_then(|i| i.as_str()) {
+                Some(i) => i.to_string(),
+                None => {
+                    warn!(self.log, "Ignoring directive without an id");
+                    continue;
+                }
+            };
+            if state.executed.contains(&id) {
+                continue;
+            }
+            // Recorded first so a directive is never run twice, even if it reboots
+            state.executed.push(id.clone());
+            if state.executed.len() > EXECUTED_KEPT {
+                state.executed.remove(0);
+            }
+            write_directive_state(&self.state_dir, &state).context(ErrorKind::Disk)?;
+            let mut result = DirectiveResult {
+                id: id.clone(),
+                device: self.device.clone(),
+                outcome: Outcome::Done,
+                error: None,
+                object: None,
+                finished: Utc::now(),
+            };
+            match serde_json::from_value::<Directive>(value) {
+                Err(e) => {
+                    warn!(
+                        self.log,
+                        "Rejecting directive",
+                        id = &id,
+                        err = e.to_string()
+                    );
+                    result.outcome = Outcome::Rejected;
+                    result.error = Some(format!("Invalid or unknown directive: {}", e));
+                }
+                Ok(d) if d.expires.map(|e| e < clock::now()).unwrap_or(false) => {
+                    info!(self.log, "Directive expired", id = &id);
+                    result.outcome = Outcome::Expired;
+                }
+                Ok(d) => {
+                    info!(
+                        self.log,
+                        "Running directive",
+                        id = &id,
+                        action = d.action.name()
+                    );
+                    match self.execute(&d, &mut requested) {
+                        Ok(object) => result.object = object,
+                        Err(e) => {
+                            warn!(
+                                self.log,
+                                "Directive failed",
+                                id = &id,
+                                err = format!("{:?}", e)
+                            );
+                            result.outcome = Outcome::Failed;
+                            result.error = Some(format!("{:#}", e));
+                        }
+                    }
+                }
+            }
+            result.finished = Utc::now();
+            let result_key = self.key(&format!("results/{}.json", id));
+            if let Err(e) = self.store.put(
+                &result_key,
+                &serde_json::to_vec_pretty(&result).unwrap(),
+                "application/json",
+            ) {
+                warn!(
+                    self.log,
+                    "Failed to acknowledge directive",
+                    id = &id,
+                    err = format!("{:?}", e)
+                );
+            }
+        }
+        Ok(requested)
+    }
+
+    // Returns the key of any uploaded object
+    fn execute(&self, directive: &Directive, requested: &mut Requested) -> Result<Option<String>> {
+        match &directive.action {
+            Action::Reboot => requested.reboot = true,
+            Action::Check => requested.check = true,
+            Action::ResetVar => {
+                let path = self.state_dir.join(RESET_VAR_FILE);
+                File::create(&path)
+                    .with_context(|| format!("Failed to create {}", path.to_string_lossy()))
+                    .context(ErrorKind::Disk)?;
+                requested.reboot = true;
+            }
+            Action::UploadLogs { lines } => {
+                let output = self
+                    .runner
+                    .output(Command::new("journalctl").args([
+                        "--boot",
+                        "--no-pager",
+                        "--output=short-iso",
+                        &format!("--lines={}", lines.unwrap_or(DEFAULT_LOG_LINES)),
+                    ]))
+                    .context("Failed to run journalctl")?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "journalctl failed: {}",
+                        String::from_utf8_lossy(&output.stderr)
+                    ));
+                }
+                let key = self.key(&format!("logs/{}.log", directive.id));
+                self.store.put(&key, &output.stdout, "text/plain")?;
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::{fake_output, FakeRunner},
+        tests::logger,
+    };
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    fn directives(
+        store: &Arc<MemoryDirectiveStore>,
+        device: &str,
+        state_dir: PathBuf,
+        runner: Arc<FakeRunner>,
+    ) -> Directives {
+        let config: DirectivesConfig = serde_json::from_value(serde_json::json!({
+            "credentials": {"type": "file"},
+        }))
+        .unwrap();
+        Directives::new(
+            logger(),
+            config,
+            device.to_string(),
+            state_dir,
+            Box::new(store.clone()),
+            runner,
+        )
+    }
+
+    #[test]
+    fn directives_run_once_and_are_acknowledged() {
+        let dir = TempDir::new().unwrap();
+        let store = Arc::new(MemoryDirectiveStore::default());
+        let runner = Arc::new(FakeRunner::new(|line| match line[0].as_str() {
+            "journalctl" => Some(fake_output(0, b"boot log\n")),
+            _ => None,
+        }));
+        let issued = chrono::Utc::now().to_rfc3339();
+        let past = (chrono::Utc::now() - Duration::minutes(1)).to_rfc3339();
+        store.publish(
+            "dev1",
+            serde_json::json!([
+                { "id": "a", "action": "check", "issued": issued },
+                { "id": "b", "action": "upload_logs", "lines": 5, "issued": issued },
+                { "id": "c", "action": "format_disk", "issued": issued },
+                { "id": "d", "action": "reboot", "issued": issued, "expires": past },
+            ]),
+        );
+        let d = directives(&store, "dev1", dir.path().to_path_buf(), runner.clone());
+        assert_eq!(
+            d.run().unwrap(),
+            Requested {
+                check: true,
+                reboot: false
+            }
+        );
+        let outcome = |id| store.result("dev1", id).unwrap().outcome;
+        assert_eq!(outcome("a"), Outcome::Done);
+        assert_eq!(outcome("c"), Outcome::Rejected);
+        assert_eq!(outcome("d"), Outcome::Expired);
+        let logs = store.result("dev1", "b").unwrap();
+        assert_eq!(logs.outcome, Outcome::Done);
+        assert_eq!(logs.object.as_deref(), Some("devices/dev1/logs/b.log"));
+        assert_eq!(
+            store.get("devices/dev1/logs/b.log").unwrap().unwrap(),
+            b"boot log\n"
+        );
+        assert!(runner.calls_to("journalctl")[0].contains(&"--lines=5".to_string()));
+
+        // Directives left in the object aren't run again
+        assert_eq!(d.run().unwrap(), Requested::default());
+        assert_eq!(runner.calls_to("journalctl").len(), 1);
+
+        // Objects for other devices are refused
+        store.publish("dev2", serde_json::json!([]));
+        store
+            .put(
+                "devices/dev1/directives.json",
+                &store.get("devices/dev2/directives.json").unwrap().unwrap(),
+                "application/json",
+            )
+            .unwrap();
+        assert!(d.run().is_err());
+    }
+}
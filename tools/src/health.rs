@@ -0,0 +1,310 @@
+use crate::{http::HttpClient, info, runner::Runner, warn, SUCCESS_UNIT};
+use anyhow::{anyhow, Result};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::process::Command;
+
+fn default_deadline() -> u64 {
+    300
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct HealthChecks {
+    // Systemd units that must be active
+    #[serde(default)]
+    pub units: Vec<String>,
+    // Urls that must respond with 200
+    #[serde(default)]
+    pub http: Vec<String>,
+    // Commands (program then args) that must exit with 0
+    #[serde(default)]
+    pub commands: Vec<Vec<String>>,
+    // Seconds after `success` starts to keep retrying failing checks
+    #[serde(default = "default_deadline")]
+    pub deadline: u64,
+}
+
+impl HealthChecks {
+    pub fn deadline(&self) -> Duration {
+        Duration::seconds(self.deadline as i64)
+    }
+}
+
+fn check_unit(runner: &dyn Runner, unit: &str) -> Result<()> {
+    let output = runner
+        .output(Command::new("systemctl").arg("is-active").arg(unit))
+        .map_err(|e| anyhow!("Failed to run systemctl is-active").context(e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Unit isn't active: {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn check_http(http: &HttpClient, url: &str) -> Result<()> {
+    let (status, _) = http.get_bytes(url)?;
+    if status != 200 {
+        return Err(anyhow!("Got status {}", status));
+    }
+    Ok(())
+}
+
+fn check_command(runner: &dyn Runner, command: &[String]) -> Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("Health check command is empty"))?;
+    let output = runner
+        .output(Command::new(program).args(args))
+        .map_err(|e| anyhow!("Failed to run command").context(e))?;
+    if !output.status.success() {
+        return Err(anyhow!("Exit code indicated error: {:?}", output));
+    }
+    Ok(())
+}
+
+// Only a unit that has failed counts as a definitive failure, everything else
+// may still recover before the deadline
+pub fn unit_failed(runner: &dyn Runner, unit: &str) -> Result<bool> {
+    let output = runner
+        .output(
+            Command::new("systemctl")
+                .arg("is-failed")
+                .arg("--quiet")
+                .arg(unit),
+        )
+        .map_err(|e| anyhow!("Failed to run systemctl is-failed").context(e))?;
+    Ok(output.status.success())
+}
+
+pub fn unit_active(runner: &dyn Runner, unit: &str) -> Result<bool> {
+    Ok(check_unit(runner, unit).is_ok())
+}
+
+// Runs every check, logging each result. Fails if any check failed.
+pub fn check_health(
+    log: &Logger,
+    runner: &dyn Runner,
+    http: &HttpClient,
+    checks: &HealthChecks,
+) -> Result<()> {
+    let mut failed = 0;
+    let mut record = |kind: &str, name: String, res: Result<()>| match res {
+        Ok(()) => {
+            info!(log, "Health check passed", kind = kind, check = name);
+        }
+        Err(e) => {
+            failed += 1;
+            warn!(
+                log,
+                "Health check failed",
+                kind = kind,
+                check = name,
+                err = format!("{:?}", e)
+            );
+        }
+    };
+    for unit in &checks.units {
+        record("unit", unit.clone(), check_unit(runner, unit));
+    }
+    for url in &checks.http {
+        record("http", url.clone(), check_http(http, url));
+    }
+    for command in &checks.commands {
+        record("command", command.join(" "), check_command(runner, command));
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} health checks failed", failed));
+    }
+    Ok(())
+}
+
+// Watches a boot of a new version until it's marked successful, polling every `poll`. Returns
+// true if it should be rolled back, because the success unit or one of `units` failed or the
+// boot wasn't marked successful within `deadline`.
+pub fn watch_boot(
+    log: &Logger,
+    runner: &dyn Runner,
+    units: &[String],
+    deadline: Duration,
+    poll: Duration,
+) -> Result<bool> {
+    let start = Utc::now();
+    loop {
+        if unit_active(runner, SUCCESS_UNIT)? {
+            info!(log, "Boot marked successful");
+            return Ok(false);
+        }
+        let mut failed = vec![];
+        for unit in [SUCCESS_UNIT]
+            .into_iter()
+            .chain(units.iter().map(|u| u.as_str()))
+        {
+            if unit_failed(runner, unit)? {
+                failed.push(unit);
+            }
+        }
+        if !failed.is_empty() {
+            warn!(
+                log,
+                "Units failed, rolling back",
+                units = format!("{:?}", failed)
+            );
+            return Ok(true);
+        }
+        if Utc::now() - start >= deadline {
+            warn!(
+                log,
+                "Boot wasn't marked successful before deadline, rolling back"
+            );
+            return Ok(true);
+        }
+        std::thread::sleep(poll.to_std().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::{fake_output, FakeRunner},
+        tests::logger,
+    };
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    fn http() -> HttpClient {
+        HttpClient::new(&Default::default()).unwrap()
+    }
+
+    fn checks(units: &[&str], commands: &[&[&str]]) -> HealthChecks {
+        HealthChecks {
+            units: units.iter().map(|u| u.to_string()).collect(),
+            http: vec![],
+            commands: commands
+                .iter()
+                .map(|c| c.iter().map(|a| a.to_string()).collect())
+                .collect(),
+            deadline: 0,
+        }
+    }
+
+    #[test]
+    fn health_checks_run_through_runner() {
+        let runner = FakeRunner::new(|_| None);
+        let checks = checks(&["app.service"], &[&["curl", "-f", "localhost"]]);
+        check_health(&logger(), &runner, &http(), &checks).unwrap();
+        assert_eq!(
+            runner.calls(),
+            vec![
+                vec!["systemctl", "is-active", "app.service"],
+                vec!["curl", "-f", "localhost"],
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_checks_are_counted() {
+        let runner = FakeRunner::new(|c| match c[0].as_str() {
+            "systemctl" => Some(fake_output(3, b"inactive\n")),
+            "false" => Some(fake_output(1, b"")),
+            _ => None,
+        });
+        let checks = checks(&["app.service"], &[&["false"], &["true"]]);
+        let e = check_health(&logger(), &runner, &http(), &checks).unwrap_err();
+        assert_eq!(e.to_string(), "2 health checks failed");
+        assert_eq!(runner.calls().len(), 3);
+    }
+
+    #[test]
+    fn http_checks_need_200() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut r = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    r.read_line(&mut head).unwrap();
+                }
+                write!(
+                    r.get_mut(),
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        let mut checks = checks(&[], &[]);
+        checks.http = vec![url];
+        let runner = FakeRunner::new(|_| None);
+        assert!(check_health(&logger(), &runner, &http(), &checks).is_err());
+        check_health(&logger(), &runner, &http(), &checks).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_boot_stops_once_marked_successful() {
+        let runner = FakeRunner::new(|c| match c[1].as_str() {
+            "is-active" => Some(fake_output(0, b"active\n")),
+            _ => None,
+        });
+        let units = vec!["app.service".to_string()];
+        let rollback = watch_boot(
+            &logger(),
+            &runner,
+            &units,
+            Duration::seconds(60),
+            Duration::zero(),
+        )
+        .unwrap();
+        assert!(!rollback);
+        assert!(runner.calls().iter().all(|c| c[1] != "is-failed"));
+    }
+
+    #[test]
+    fn watch_boot_rolls_back_on_failed_unit() {
+        let runner = FakeRunner::new(|c| match (c[1].as_str(), c.last().unwrap().as_str()) {
+            ("is-active", _) => Some(fake_output(3, b"activating\n")),
+            ("is-failed", "app.service") => Some(fake_output(0, b"")),
+            ("is-failed", _) => Some(fake_output(1, b"")),
+            _ => None,
+        });
+        let units = vec!["app.service".to_string()];
+        let rollback = watch_boot(
+            &logger(),
+            &runner,
+            &units,
+            Duration::seconds(60),
+            Duration::zero(),
+        )
+        .unwrap();
+        assert!(rollback);
+        assert_eq!(
+            runner.calls_to("systemctl")[1..],
+            [
+                vec!["systemctl", "is-failed", "--quiet", SUCCESS_UNIT],
+                vec!["systemctl", "is-failed", "--quiet", "app.service"],
+            ]
+        );
+    }
+
+    #[test]
+    fn watch_boot_rolls_back_after_deadline() {
+        let runner = FakeRunner::new(|c| match c[1].as_str() {
+            "is-active" => Some(fake_output(3, b"activating\n")),
+            "is-failed" => Some(fake_output(1, b"")),
+            _ => None,
+        });
+        let rollback =
+            watch_boot(&logger(), &runner, &[], Duration::zero(), Duration::zero()).unwrap();
+        assert!(rollback);
+        assert_eq!(runner.calls().len(), 2);
+    }
+}
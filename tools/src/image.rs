@@ -0,0 +1,116 @@
+// Running the tools against a disk image file instead of the machine's disks
+use crate::{
+    ec, grub_env_list, info, mount_boot, read_slots, warn, Host, InternalMeta, SimpleCommand,
+    GRUB_NEW_VAR, GRUB_SAVED_VAR,
+};
+use anyhow::{anyhow, Context, Result};
+use slog::Logger;
+use std::{
+    fs::create_dir,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+
+// An image attached to a loop device with partition scanning, detached on drop. The boot
+// partition is mounted in a temp dir rather than on /boot.
+pub struct DiskImage {
+    log: Logger,
+    base: Host,
+    pub device: PathBuf,
+    dir: TempDir,
+}
+
+impl DiskImage {
+    pub fn attach(log: Logger, base: &Host, path: &Path) -> Result<DiskImage> {
+        if !path.exists() {
+            return Err(anyhow!(
+                "Disk image {} doesn't exist",
+                path.to_string_lossy()
+            ));
+        }
+        let dir = TempDir::new().context("Failed to create temp dir")?;
+        create_dir(dir.path().join("boot")).context("Failed to create boot mount dir")?;
+        let output = base
+            .runner
+            .output(
+                Command::new("losetup")
+                    .arg("--find")
+                    .arg("--show")
+                    .arg("--partscan")
+                    .arg(path),
+            )
+            .context("Failed to run losetup")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Attaching {} to a loop device failed: {:?}",
+                path.to_string_lossy(),
+                output
+            ));
+        }
+        let device = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        info!(
+            log,
+            "Attached disk image",
+            image = path.to_string_lossy().to_string(),
+            device = device.to_string_lossy().to_string()
+        );
+        Ok(DiskImage {
+            log: log,
+            base: base.clone(),
+            device: device,
+            dir: dir,
+        })
+    }
+
+    pub fn host(&self) -> Host {
+        Host {
+            boot_dir: self.dir.path().join("boot"),
+            disk: Some(self.device.clone()),
+            ..self.base.clone()
+        }
+    }
+
+    // An image is never booted so no failed versions are found, only kept for this run
+    pub fn state_dir(&self) -> PathBuf {
+        self.dir.path().join("state")
+    }
+
+    // The version the image would boot: the pending new version, the last known good version,
+    // or for a freshly initialized image the only installed version
+    pub fn current_meta(&self) -> Result<InternalMeta> {
+        let host = self.host();
+        ec!(("Error reading current version from image"), {
+            let _mount = mount_boot(self.log.clone(), &host)?;
+            let env = grub_env_list(&host)?;
+            let slots = read_slots(&host)?;
+            let uuid = match env.get(GRUB_NEW_VAR).or_else(|| env.get(GRUB_SAVED_VAR)) {
+                Some(u) => u,
+                None if slots.len() == 1 => return Ok(slots[0].meta.clone()),
+                None => return Err(anyhow!("No version is marked as default")),
+            };
+            slots
+                .into_iter()
+                .find(|s| &s.meta.uuid == uuid)
+                .map(|s| s.meta)
+                .ok_or_else(|| anyhow!("Default version {} isn't in any slot", uuid))
+        })
+    }
+}
+
+impl Drop for DiskImage {
+    fn drop(&mut self) {
+        if let Err(e) = Command::new("losetup")
+            .arg("--detach")
+            .arg(&self.device)
+            .run(&*self.base.runner)
+        {
+            warn!(
+                self.log,
+                "Failed to detach loop device",
+                device = self.device.to_string_lossy().to_string(),
+                err = format!("{:?}", e)
+            );
+        }
+    }
+}
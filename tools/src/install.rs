@@ -0,0 +1,247 @@
+// First time installation of a disk, used by the installer image
+use crate::{
+    blockdev::block_devices,
+    copy_finish,
+    credentials::{write_credentials_file, KeyCredentials, CREDENTIALS_FILE},
+    ec, find_root_parts, mount_boot, part_type_guid, retry, root_label, write_slots, ErrorKind,
+    ExternalMeta, Host, InternalMeta, Mount, RetryPolicy, SimpleCommand, SlotState, BOOT_LABEL,
+    MAX_ROOT_SLOTS, RW_LABEL,
+};
+use anyhow::{anyhow, Context, Result};
+use askama::Template;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    fs::{create_dir_all, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::TempDir;
+use zstd::stream::{raw::Decoder, zio::Writer};
+
+#[derive(Template)]
+#[template(path = "grub_one.conf", escape = "none")]
+struct GrubTemplate<'a> {
+    new: &'a InternalMeta,
+}
+
+fn default_slots() -> usize {
+    2
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InitConfig {
+    pub size: u64,
+    // Number of root partitions
+    #[serde(default = "default_slots")]
+    pub slots: usize,
+    pub version: ExternalMeta,
+    pub version_path: PathBuf,
+    // Written to the rw partition for the `file` credential source
+    #[serde(default)]
+    pub credentials: Option<KeyCredentials>,
+}
+
+// Partitions the first disk (or the host's disk), writes the initial version to the first root
+// slot and installs grub
+pub fn install(log: &Logger, host: &Host, config: &InitConfig) -> Result<()> {
+    if config.slots < 2 || config.slots > MAX_ROOT_SLOTS {
+        return Err(anyhow!(
+            "Number of root slots must be between 2 and {}, got {}",
+            MAX_ROOT_SLOTS,
+            config.slots
+        )
+        .context(ErrorKind::Config));
+    }
+
+    let root_disk = match block_devices(host)
+        .context(ErrorKind::Disk)?
+        .into_iter()
+        .filter(|d| d.is_disk())
+        .next()
+    {
+        Some(d) => d,
+        None => {
+            return Err(anyhow!(
+                "When looking for a disk to use as the root disk, couldn't find any disks"
+            )
+            .context(ErrorKind::Disk));
+        }
+    };
+
+    // Partition
+    {
+        let mut c = Command::new("parted");
+        c.arg("--script")
+            .arg(&root_disk.path)
+            .arg("--")
+            // Drive config
+            .arg("mklabel")
+            .arg("gpt");
+
+        let mut part = 0;
+        // Partition number and label of each organixm partition
+        let mut labels = vec![];
+
+        // Grub part
+        part += 1;
+        let mut off = 1;
+        c.arg("mkpart").arg("no-fs");
+        c.arg(format!("{}MiB", off));
+        off += 1;
+        c.arg(format!("{}MiB", off));
+        c.arg("set").arg("1").arg("bios_grub").arg("on");
+
+        // Boot files
+        part += 1;
+        c.arg("mkpart").arg("primary").arg("ext4");
+        c.arg(format!("{}MiB", off));
+        off += 127;
+        c.arg(format!("{}MiB", off));
+        c.arg("name").arg(format!("{}", part)).arg(BOOT_LABEL);
+        labels.push((part, BOOT_LABEL.to_string()));
+        c.arg("align-check").arg("optimal").arg(format!("{}", part));
+
+        // Roots
+        for slot in 0..config.slots {
+            part += 1;
+            c.arg("mkpart").arg("primary").arg("ext4");
+            c.arg(format!("{}MiB", off));
+            off += config.size * 1024;
+            c.arg(format!("{}MiB", off));
+            c.arg("name").arg(format!("{}", part)).arg(root_label(slot));
+            labels.push((part, root_label(slot)));
+            c.arg("align-check").arg("optimal").arg(format!("{}", part));
+        }
+
+        // RW
+        part += 1;
+        c.arg("mkpart").arg("primary").arg("ext4");
+        c.arg(format!("{}MiB", off));
+        c.arg("-1");
+        c.arg("name").arg(format!("{}", part)).arg(RW_LABEL);
+        labels.push((part, RW_LABEL.to_string()));
+        c.arg("align-check").arg("optimal").arg(format!("{}", part));
+
+        c.run(&*host.runner).context(ErrorKind::Disk)?;
+
+        // Parted can't set arbitrary type guids
+        for (part, label) in labels {
+            Command::new("sfdisk")
+                .arg("--part-type")
+                .arg(&root_disk.path)
+                .arg(format!("{}", part))
+                .arg(part_type_guid(&label).unwrap())
+                .run(&*host.runner)
+                .context(ErrorKind::Disk)?;
+        }
+    }
+
+    let mut part_paths = vec![];
+    for label in [RW_LABEL, BOOT_LABEL] {
+        part_paths.push(
+            retry(
+                log,
+                &RetryPolicy::fixed(Duration::minutes(5), Duration::seconds(10)),
+                || {
+                    let path = host.part_path(label)?;
+                    if path.exists() {
+                        return Ok(path);
+                    } else {
+                        return Err(anyhow!(
+                            "{} doesn't exist yet, needed for mkfs",
+                            path.to_string_lossy()
+                        ));
+                    }
+                },
+            )
+            .context(ErrorKind::Disk)?,
+        );
+    }
+    let (rw_path, boot_path) = (&part_paths[0], &part_paths[1]);
+
+    Command::new("mkfs.ext4")
+        .arg(boot_path)
+        .run(&*host.runner)
+        .context(ErrorKind::Disk)?;
+    Command::new("mkfs.ext4")
+        .arg(rw_path)
+        .run(&*host.runner)
+        .context(ErrorKind::Disk)?;
+
+    if let Some(credentials) = &config.credentials {
+        ec!(("Error storing credentials on rw partition"), {
+            let rw_dir = TempDir::new().context("Failed to create temp dir")?;
+            let _mount = Mount::new(log.clone(), host.runner.clone(), rw_path, rw_dir.path())?;
+            // Mounted at /rw on the installed system
+            write_credentials_file(
+                &rw_dir.path().join("organixm").join(CREDENTIALS_FILE),
+                credentials,
+            )
+        })
+        .context(ErrorKind::Disk)?;
+    }
+
+    // Install the first version + grub
+    let root_part = find_root_parts(log, host).context(ErrorKind::Disk)?.1[0].clone();
+
+    ec!(
+        (
+            "Error writing initial image from {} to {}",
+            config.version_path.to_string_lossy(),
+            &root_part.path
+        ),
+        Ok(copy_finish(
+            &mut File::open(&config.version_path).context("Unable to open source")?,
+            &mut Writer::new(
+                &mut BufWriter::new(
+                    &mut File::create(Path::new(&root_part.path)).context("Unable to open dest")?
+                ),
+                Decoder::new().unwrap(),
+            ),
+        )?)
+    )
+    .context(ErrorKind::Disk)?;
+
+    ec!(("Error installing grub"), {
+        create_dir_all(&host.boot_dir).map_err(|e| {
+            anyhow!("Failed to create {}", host.boot_dir.to_string_lossy()).context(e)
+        })?;
+        let _mount = mount_boot(log.clone(), host)?;
+        let grub_dir = host.boot_dir.join("grub");
+        create_dir_all(&grub_dir).context("Failed to ensure grub dir")?;
+        write_slots(
+            host,
+            &[SlotState {
+                label: root_part.partlabel.clone().unwrap(),
+                meta: config.version.internal.clone(),
+                installed: Utc::now(),
+            }],
+        )?;
+        File::create(grub_dir.join("grub.cfg"))
+            .context("Unable to open grub.cfg for writing")?
+            .write_all(
+                GrubTemplate {
+                    new: &config.version.internal,
+                }
+                .render()
+                .unwrap()
+                .as_ref(),
+            )
+            .context("Error writing to grub.cfg")?;
+        Command::new("grub-install")
+            .arg("--target=i386-pc")
+            .arg(format!(
+                "--boot-directory={}",
+                host.boot_dir.to_string_lossy()
+            ))
+            .arg(&root_disk.path)
+            .run(&*host.runner)?;
+        Ok(())
+    })
+    .context(ErrorKind::Bootloader)?;
+
+    Ok(())
+}
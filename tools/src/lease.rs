@@ -0,0 +1,521 @@
+// Reboot coordination: a device takes one of a group's N lease objects in the bucket before
+// rebooting into a new version and releases it once the new version is marked good, so only N
+// devices in the group are rebooting at a time. Objects are claimed with conditional writes and
+// a lease that's never released (ex: the device died) can be taken over after its TTL.
+use crate::{
+    check_s3_status, clock,
+    credentials::{CredentialProvider, CredentialSource},
+    ec,
+    http::HttpClient,
+    info, presign_url, read_bytes, warn, ErrorKind, InternalMeta,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    fs::{create_dir_all, remove_file, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const PRESIGN_EXPIRY_SECS: u32 = 300;
+
+fn default_lease_count() -> u32 {
+    1
+}
+
+fn default_lease_ttl() -> u64 {
+    1800
+}
+
+fn default_lease_wait() -> u64 {
+    3600
+}
+
+fn default_lease_prefix() -> String {
+    "leases".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LeaseConfig {
+    // Devices in the same group share the leases (ex: a venue)
+    pub group: String,
+    // How many devices in the group may reboot at once
+    #[serde(default = "default_lease_count")]
+    pub count: u32,
+    // Seconds after which an unreleased lease can be taken by another device
+    #[serde(default = "default_lease_ttl")]
+    pub ttl: u64,
+    // Seconds to wait for a free lease before giving up until the next update run
+    #[serde(default = "default_lease_wait")]
+    pub wait: u64,
+    // Key prefix in the version bucket, leases are `<prefix>/<group>/<n>`
+    #[serde(default = "default_lease_prefix")]
+    pub prefix: String,
+    // Must be able to put and delete objects under the prefix. Defaults to the version's
+    // credentials.
+    #[serde(default)]
+    pub credentials: Option<CredentialSource>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Lease {
+    // Id of the device holding the lease
+    pub holder: String,
+    // Version being rebooted into
+    pub version: String,
+    pub acquired: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+// A lease this device took, kept so it can be released after rebooting
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HeldLease {
+    pub key: String,
+    pub etag: String,
+    pub lease: Lease,
+}
+
+fn held_lease_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("lease.json")
+}
+
+pub fn read_held_lease(state_dir: &Path) -> Result<Option<HeldLease>> {
+    let path = held_lease_path(state_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse held lease")?,
+    ))
+}
+
+pub fn write_held_lease(state_dir: &Path, held: &HeldLease) -> Result<()> {
+    let path = held_lease_path(state_dir);
+    ec!(("Recording held lease in {}", path.to_string_lossy()), {
+        create_dir_all(state_dir).context("Failed to create state dir")?;
+        File::create(&path)
+            .context("Failed to open for writing")?
+            .write_all(&serde_json::to_vec_pretty(held).unwrap())
+            .context("Failed to write")?;
+        Ok(())
+    })
+}
+
+pub fn clear_held_lease(state_dir: &Path) -> Result<()> {
+    let path = held_lease_path(state_dir);
+    ec!(("Clearing held lease in {}", path.to_string_lossy()), {
+        if path.exists() {
+            remove_file(&path).context("Failed to remove")?;
+        }
+        Ok(())
+    })
+}
+
+// Identifies the device in leases. Kept in the state dir since /etc (and its machine id) is
+// recreated each boot.
+pub fn device_id(state_dir: &Path) -> Result<String> {
+    let path = state_dir.join("device_id");
+    ec!(
+        ("Error getting device id from {}", path.to_string_lossy()),
+        {
+            if path.exists() {
+                return Ok(String::from_utf8(read_bytes(&path)?)
+                    .context("Device id isn't utf-8")?
+                    .trim()
+                    .to_string());
+            }
+            let id = format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..));
+            create_dir_all(state_dir).context("Failed to create state dir")?;
+            File::create(&path)
+                .context("Failed to open for writing")?
+                .write_all(id.as_bytes())
+                .context("Failed to write")?;
+            Ok(id)
+        }
+    )
+}
+
+// Where the lease objects are kept. Writes are conditional on the object's etag so two devices
+// can't both take the same lease.
+pub trait LeaseStore {
+    // The lease and its etag, if the object exists
+    fn get(&self, key: &str) -> Result<Option<(Lease, String)>>;
+    // Creates the object if it doesn't exist. Returns the new etag, or None if it exists.
+    fn create(&self, key: &str, lease: &Lease) -> Result<Option<String>>;
+    // Replaces the object if it still has `etag`. Returns the new etag, or None if it changed.
+    fn replace(&self, key: &str, lease: &Lease, etag: &str) -> Result<Option<String>>;
+    fn delete(&self, key: &str, etag: &str) -> Result<()>;
+}
+
+// One bucket shared by several devices
+impl<T: LeaseStore + ?Sized> LeaseStore for Arc<T> {
+    fn get(&self, key: &str) -> Result<Option<(Lease, String)>> {
+        (**self).get(key)
+    }
+
+    fn create(&self, key: &str, lease: &Lease) -> Result<Option<String>> {
+        (**self).create(key, lease)
+    }
+
+    fn replace(&self, key: &str, lease: &Lease, etag: &str) -> Result<Option<String>> {
+        (**self).replace(key, lease, etag)
+    }
+
+    fn delete(&self, key: &str, etag: &str) -> Result<()> {
+        (**self).delete(key, etag)
+    }
+}
+
+pub struct S3LeaseStore {
+    bucket: InternalMeta,
+    credentials: CredentialProvider,
+    http: HttpClient,
+}
+
+impl S3LeaseStore {
+    pub fn new(current: &InternalMeta, config: &LeaseConfig) -> Result<S3LeaseStore> {
+        let http = HttpClient::new(&current.http).context(ErrorKind::Config)?;
+        Ok(S3LeaseStore {
+            bucket: current.clone(),
+            credentials: CredentialProvider::new(
+                match &config.credentials {
+                    Some(c) => c.clone(),
+                    None => CredentialSource::of(current),
+                },
+                &http,
+            ),
+            http: http,
+        })
+    }
+
+    fn presign(&self, method: &str, key: &str) -> Result<String> {
+        let credentials = self.credentials.get().context(ErrorKind::Credentials)?;
+        presign_url(&self.bucket, credentials, method, key, PRESIGN_EXPIRY_SECS)
+    }
+
+    fn put(&self, key: &str, lease: &Lease, condition: (&str, &str)) -> Result<Option<String>> {
+        let url = self.presign("PUT", key)?;
+        let body = serde_json::to_vec(lease).unwrap();
+        let (status, headers, _) = self
+            .http
+            .send(
+                "PUT",
+                &url,
+                &[condition, ("Content-Type", "application/json")],
+                &body,
+            )
+            .context(ErrorKind::Network)?;
+        // 409: a concurrent conditional write to the same key is in progress
+        if status == 412 || status == 409 {
+            return Ok(None);
+        }
+        check_s3_status(status)?;
+        Ok(Some(headers.get("etag").cloned().ok_or_else(|| {
+            anyhow!("Lease write response has no etag").context(ErrorKind::Server)
+        })?))
+    }
+}
+
+impl LeaseStore for S3LeaseStore {
+    fn get(&self, key: &str) -> Result<Option<(Lease, String)>> {
+        let url = self.presign("GET", key)?;
+        let (status, headers, body) = self
+            .http
+            .send("GET", &url, &[], &[])
+            .context(ErrorKind::Network)?;
+        if status == 404 {
+            return Ok(None);
+        }
+        check_s3_status(status)?;
+        let etag = headers
+            .get("etag")
+            .cloned()
+            .ok_or_else(|| anyhow!("Lease response has no etag").context(ErrorKind::Server))?;
+        Ok(Some((
+            serde_json::from_slice(&body)
+                .with_context(|| format!("Invalid lease object {}", key))
+                .context(ErrorKind::Server)?,
+            etag,
+        )))
+    }
+
+    fn create(&self, key: &str, lease: &Lease) -> Result<Option<String>> {
+        self.put(key, lease, ("If-None-Match", "*"))
+    }
+
+    fn replace(&self, key: &str, lease: &Lease, etag: &str) -> Result<Option<String>> {
+        self.put(key, lease, ("If-Match", etag))
+    }
+
+    fn delete(&self, key: &str, etag: &str) -> Result<()> {
+        let url = self.presign("DELETE", key)?;
+        let (status, _, _) = self
+            .http
+            .send("DELETE", &url, &[("If-Match", etag)], &[])
+            .context(ErrorKind::Network)?;
+        // Already gone or taken over
+        if status == 404 || status == 412 {
+            return Ok(());
+        }
+        check_s3_status(status)
+    }
+}
+
+// Leases kept in memory, for tests
+#[cfg(any(test, feature = "testing"))]
+#[derive(Default)]
+pub struct MemoryLeaseStore {
+    objects: std::sync::Mutex<std::collections::HashMap<String, (Lease, String)>>,
+    next_etag: std::sync::Mutex<u32>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MemoryLeaseStore {
+    fn etag(&self) -> String {
+        let mut next = self.next_etag.lock().unwrap();
+        *next += 1;
+        format!("\"{}\"", next)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl LeaseStore for MemoryLeaseStore {
+    fn get(&self, key: &str) -> Result<Option<(Lease, String)>> {
+        Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+
+    fn create(&self, key: &str, lease: &Lease) -> Result<Option<String>> {
+        let mut objects = self.objects.lock().unwrap();
+        if objects.contains_key(key) {
+            return Ok(None);
+        }
+        let etag = self.etag();
+        objects.insert(key.to_string(), (lease.clone(), etag.clone()));
+        Ok(Some(etag))
+    }
+
+    fn replace(&self, key: &str, lease: &Lease, etag: &str) -> Result<Option<String>> {
+        let mut objects = self.objects.lock().unwrap();
+        if objects.get(key).map(|o| o.1.as_str()) != Some(etag) {
+            return Ok(None);
+        }
+        let etag = self.etag();
+        objects.insert(key.to_string(), (lease.clone(), etag.clone()));
+        Ok(Some(etag))
+    }
+
+    fn delete(&self, key: &str, etag: &str) -> Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        if objects.get(key).map(|o| o.1.as_str()) == Some(etag) {
+            objects.remove(key);
+        }
+        Ok(())
+    }
+}
+
+pub struct Leases {
+    log: Logger,
+    pub config: LeaseConfig,
+    // This device
+    holder: String,
+    store: Box<dyn LeaseStore>,
+}
+
+impl Leases {
+    pub fn new(
+        log: Logger,
+        config: LeaseConfig,
+        holder: String,
+        store: Box<dyn LeaseStore>,
+    ) -> Leases {
+        Leases {
+            log: log,
+            config: config,
+            holder: holder,
+            store: store,
+        }
+    }
+
+    // For the running system, if the version has leases configured
+    pub fn system(log: Logger, current: &InternalMeta, state_dir: &Path) -> Result<Option<Leases>> {
+        let config = match &current.reboot_lease {
+            Some(c) => c.clone(),
+            None => return Ok(None),
+        };
+        let store = S3LeaseStore::new(current, &config)?;
+        Ok(Some(Leases::new(
+            log,
+            config,
+            device_id(state_dir).context(ErrorKind::Disk)?,
+            Box::new(store),
+        )))
+    }
+
+    fn key(&self, index: u32) -> String {
+        format!(
+            "{}/{}/{}",
+            self.config.prefix.trim_end_matches('/'),
+            self.config.group,
+            index
+        )
+    }
+
+    // Tries each of the group's leases once. Returns None if they're all held.
+    pub fn try_acquire(&self, version: &str) -> Result<Option<HeldLease>> {
+        let now = clock::now();
+        let lease = Lease {
+            holder: self.holder.clone(),
+            version: version.to_string(),
+            acquired: now,
+            expires: now + Duration::seconds(self.config.ttl as i64),
+        };
+        for index in 0..self.config.count {
+            let key = self.key(index);
+            let etag = match self.store.get(&key)? {
+                None => self.store.create(&key, &lease)?,
+                // Ours from an interrupted run, or abandoned
+                Some((existing, etag))
+                    if existing.holder == self.holder || existing.expires < now =>
+                {
+                    if existing.holder != self.holder {
+                        warn!(
+                            self.log,
+                            "Taking over expired lease",
+                            key = &key,
+                            holder = &existing.holder,
+                            expired = existing.expires.to_rfc3339()
+                        );
+                    }
+                    self.store.replace(&key, &lease, &etag)?
+                }
+                Some((existing, _)) => {
+                    info!(
+                        self.log,
+                        "Lease is held",
+                        key = &key,
+                        holder = &existing.holder,
+                        version = &existing.version,
+                        expires = existing.expires.to_rfc3339()
+                    );
+                    continue;
+                }
+            };
+            match etag {
+                Some(etag) => {
+                    info!(self.log, "Acquired reboot lease", key = &key);
+                    return Ok(Some(HeldLease {
+                        key: key,
+                        etag: etag,
+                        lease: lease,
+                    }));
+                }
+                None => info!(self.log, "Another device took the lease first", key = &key),
+            }
+        }
+        Ok(None)
+    }
+
+    // Deletes the lease unless it expired and was taken by another device since
+    pub fn release(&self, held: &HeldLease) -> Result<()> {
+        ec!(("Error releasing lease {}", held.key), {
+            match self.store.get(&held.key)? {
+                Some((lease, etag)) if lease.holder == self.holder => {
+                    self.store.delete(&held.key, &etag)?;
+                    info!(self.log, "Released reboot lease", key = &held.key);
+                }
+                _ => info!(
+                    self.log,
+                    "Reboot lease was already released or taken over",
+                    key = &held.key
+                ),
+            }
+            Ok(())
+        })
+    }
+
+    // Releases the lease recorded in `state_dir`, if any
+    pub fn release_held(&self, state_dir: &Path) -> Result<()> {
+        if let Some(held) = read_held_lease(state_dir)? {
+            self.release(&held)?;
+            clear_held_lease(state_dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::logger;
+    use std::thread;
+
+    fn leases(store: &Arc<MemoryLeaseStore>, holder: &str, count: u32, ttl: u64) -> Leases {
+        let config: LeaseConfig = serde_json::from_value(serde_json::json!({
+            "group": "venue",
+            "count": count,
+            "ttl": ttl,
+            "wait": 0,
+        }))
+        .unwrap();
+        Leases::new(
+            logger(),
+            config,
+            holder.to_string(),
+            Box::new(store.clone()),
+        )
+    }
+
+    #[test]
+    fn leases_limit_devices_per_group() {
+        let store = Arc::new(MemoryLeaseStore::default());
+        let a = leases(&store, "a", 2, 600)
+            .try_acquire("new")
+            .unwrap()
+            .unwrap();
+        let b = leases(&store, "b", 2, 600)
+            .try_acquire("new")
+            .unwrap()
+            .unwrap();
+        assert_eq!(a.key, "leases/venue/0");
+        assert_eq!(b.key, "leases/venue/1");
+        assert!(leases(&store, "c", 2, 600)
+            .try_acquire("new")
+            .unwrap()
+            .is_none());
+
+        // Releasing frees the lease, releasing again is harmless
+        leases(&store, "a", 2, 600).release(&a).unwrap();
+        leases(&store, "a", 2, 600).release(&a).unwrap();
+        let c = leases(&store, "c", 2, 600)
+            .try_acquire("new")
+            .unwrap()
+            .unwrap();
+        assert_eq!(c.key, "leases/venue/0");
+
+        // A device can't release a lease taken over by another
+        let b_leases = leases(&store, "b", 2, 600);
+        b_leases.release(&c).unwrap();
+        assert_eq!(store.get("leases/venue/0").unwrap().unwrap().0.holder, "c");
+        b_leases.release(&b).unwrap();
+        assert!(store.get("leases/venue/1").unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_leases_are_taken_over() {
+        let store = Arc::new(MemoryLeaseStore::default());
+        leases(&store, "a", 1, 0)
+            .try_acquire("new")
+            .unwrap()
+            .unwrap();
+        thread::sleep(std::time::Duration::from_millis(10));
+        let b = leases(&store, "b", 1, 600)
+            .try_acquire("new")
+            .unwrap()
+            .unwrap();
+        assert_eq!(b.lease.holder, "b");
+        assert_eq!(store.get(&b.key).unwrap().unwrap().1, b.etag);
+    }
+}
@@ -1,24 +1,161 @@
 use anyhow::{anyhow, Context, Result};
-use chrono::{Duration, Utc};
+use blockdev::block_devices;
+use chrono::{DateTime, Duration, Utc};
+use clock::ClockConfig;
+use control::ControlConfig;
+use credentials::CredentialSource;
+use directives::DirectivesConfig;
+use health::HealthChecks;
 use hhmmss::Hhmmss;
-use s3::{creds::Credentials, Bucket};
+use http::HttpConfig;
+use lease::LeaseConfig;
+use mirrors::Mirror;
+use presign::Presigner;
+use runner::{Runner, SystemRunner};
+use runtime::Schedule;
+use s3::creds::Credentials;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use slog::Logger;
 use std::{
+    collections::HashMap,
     fmt::{self},
-    fs::File,
+    fs::{create_dir_all, remove_file, File},
     io::{Read, Write},
     path::{Path, PathBuf},
     process::Command,
     str::FromStr,
+    sync::Arc,
     thread,
 };
 
+pub mod blockdev;
+pub mod clock;
+pub mod control;
+pub mod credentials;
+pub mod directives;
+pub mod health;
+pub mod http;
+pub mod image;
+pub mod install;
+pub mod lease;
+pub mod mirrors;
+pub mod network;
+pub mod notify;
+pub mod presign;
+pub mod runner;
+pub mod runtime;
 pub mod slogextra;
+pub mod system;
+pub mod updater;
 
 pub const BOOT_LABEL: &'static str = "boot";
-pub const ROOT_LABELS: [&'static str; 2] = ["organixm-a", "organixm-b"];
+pub const RW_LABEL: &'static str = "rw";
+// Gpt partition types assigned by init so organixm partitions can't be confused with others
+// that happen to share a label. Partitions are still found by label alone on installs from
+// before these were assigned.
+pub const BOOT_TYPE_GUID: &'static str = "f31101b5-18ef-45c0-a1f0-adc17cab583c";
+pub const ROOT_TYPE_GUID: &'static str = "6c5b0ecb-80cf-4d96-bd6d-e7acbba77747";
+pub const RW_TYPE_GUID: &'static str = "654e6744-23e8-4453-9215-ee5b05341b28";
+// Root slot partitions are labeled organixm-a, organixm-b, ...
+pub const ROOT_LABEL_PREFIX: &'static str = "organixm-";
+pub const MAX_ROOT_SLOTS: usize = 26;
+// Versions installed in each root slot, relative to the boot partition
+pub const SLOTS_FILE: &'static str = "organixm-slots.json";
+// Persistent state on the rw partition
+pub const RW_STATE_DIR: &'static str = "/rw/organixm";
+pub const SUCCESS_UNIT: &'static str = "organixm-success.service";
+// Relative to the boot partition
+pub const GRUB_ENV_FILE: &'static str = "grub/grubenv";
+// Last known good version, also written by grub-set-default
+pub const GRUB_SAVED_VAR: &'static str = "saved_entry";
+// Remaining boots of the new version before grub falls back to the saved entry.
+// Must match grub_slots.conf
+pub const GRUB_ATTEMPTS_VAR: &'static str = "organixm_attempts";
+// Uuid of the version that was most recently installed and hasn't been marked successful yet
+pub const GRUB_NEW_VAR: &'static str = "organixm_new";
+
+// The machine the tools act on, can be redirected for tests
+#[derive(Clone)]
+pub struct Host {
+    pub runner: Arc<dyn Runner>,
+    // Where the boot partition is mounted
+    pub boot_dir: PathBuf,
+    // Contains links to partitions named by gpt label
+    pub partlabel_dir: PathBuf,
+    // Only look at this disk, and find partitions on it rather than through `partlabel_dir`
+    pub disk: Option<PathBuf>,
+    // Block device discovery
+    pub sys_dir: PathBuf,
+    pub dev_dir: PathBuf,
+    pub mountinfo_path: PathBuf,
+}
+
+impl Host {
+    pub fn system() -> Host {
+        Host {
+            runner: Arc::new(SystemRunner),
+            boot_dir: PathBuf::from("/boot"),
+            partlabel_dir: PathBuf::from("/dev/disk/by-partlabel"),
+            disk: None,
+            sys_dir: PathBuf::from("/sys"),
+            dev_dir: PathBuf::from("/dev"),
+            mountinfo_path: PathBuf::from("/proc/self/mountinfo"),
+        }
+    }
+
+    // Path of the organixm partition with gpt label `label`, which may not exist yet. Matches
+    // the organixm partition type along with the label, falling back to just the label.
+    pub fn part_path(&self, label: &str) -> Result<PathBuf> {
+        let devices = block_devices(self)?;
+        let parts = devices.iter().flat_map(|d| &d.children);
+        let typed = parts
+            .clone()
+            .filter(|p| {
+                p.partlabel.as_deref() == Some(label)
+                    && p.parttype.is_some()
+                    && p.parttype.as_deref() == part_type_guid(label)
+            })
+            .collect::<Vec<_>>();
+        match typed.as_slice() {
+            [] => {}
+            [p] => return Ok(PathBuf::from(&p.path)),
+            _ => {
+                return Err(anyhow!(
+                    "Multiple organixm partitions labeled {}: {}",
+                    label,
+                    typed
+                        .iter()
+                        .map(|p| p.path.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        }
+        let disk = match &self.disk {
+            Some(d) => d,
+            None => return Ok(self.partlabel_dir.join(label)),
+        };
+        for part in parts {
+            if part.partlabel.as_deref() == Some(label) {
+                return Ok(PathBuf::from(&part.path));
+            }
+        }
+        Err(anyhow!(
+            "No partition labeled {} on {}",
+            label,
+            disk.to_string_lossy()
+        ))
+    }
+
+    pub fn grub_env_path(&self) -> PathBuf {
+        self.boot_dir.join(GRUB_ENV_FILE)
+    }
+
+    pub fn slots_path(&self) -> PathBuf {
+        self.boot_dir.join(SLOTS_FILE)
+    }
+}
 
 pub fn read_bytes(p: &Path) -> Result<Vec<u8>> {
     ec!(("Reading {}", p.to_string_lossy()), {
@@ -31,42 +168,6 @@ pub fn read_bytes(p: &Path) -> Result<Vec<u8>> {
     })
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct IpRoute {
-    // "default" or cidr
-    pub dst: String,
-    pub gateway: Option<String>,
-    // rel dev node
-    pub dev: String,
-}
-
-pub fn has_internet_gw() -> Result<bool> {
-    ec!(("Checking for gateway route"), {
-        let output = Command::new("ip")
-            .arg("--json")
-            .arg("route")
-            .arg("show")
-            .output()
-            .context("Failed to run ip route show")?;
-        let parsed = serde_json::from_slice::<Vec<IpRoute>>(&output.stdout)
-            .with_context(|| anyhow!("Failed to parse ip route show output:\n{:?}", &output))?;
-        for r in parsed {
-            if r.dst != "default" {
-                continue;
-            }
-            return Ok(true);
-        }
-        return Ok(false);
-    })
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct LsblkRoot {
-    pub blockdevices: Vec<LsblkDevice>,
-}
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LsblkDevice {
@@ -76,32 +177,55 @@ pub struct LsblkDevice {
     pub type_field: String,
     pub mountpoint: Option<String>,
     pub partlabel: Option<String>,
+    // Gpt partition type guid
+    #[serde(default)]
+    pub parttype: Option<String>,
     #[serde(default)]
     pub children: Vec<LsblkDevice>,
 }
 
-pub fn lsblk() -> Result<Vec<LsblkDevice>> {
-    let output = Command::new("lsblk")
-        .arg("-n")
-        .arg("-b")
-        .arg("-J")
-        .arg("-o")
-        .arg("SIZE,TYPE,PATH,MOUNTPOINT,PARTLABEL")
-        .arg("-T")
-        .output()
-        .map_err(|e| anyhow!("Failed to run lsblk").context(e))?;
-    Ok(serde_json::from_slice::<LsblkRoot>(&output.stdout)
-        .map_err(|e| anyhow!("Failed to parse lsblk output:\n{:?}", &output).context(e))?
-        .blockdevices)
-}
-
-pub fn find_root_parts(log: &Logger) -> Result<(LsblkDevice, [LsblkDevice; 2])> {
-    let mut out = vec![];
-    let lsblk_res = lsblk()?;
-    for lsblk_parent in lsblk_res {
-        if lsblk_parent.type_field != "disk" {
+impl LsblkDevice {
+    // Whole disks, including loop devices for disk images
+    pub fn is_disk(&self) -> bool {
+        self.type_field == "disk" || self.type_field == "loop"
+    }
+}
+
+pub fn root_label(slot: usize) -> String {
+    format!("{}{}", ROOT_LABEL_PREFIX, (b'a' + slot as u8) as char)
+}
+
+pub fn is_root_label(label: &str) -> bool {
+    (0..MAX_ROOT_SLOTS).any(|i| root_label(i) == label)
+}
+
+// The partition type init assigns to the partition labeled `label`
+pub fn part_type_guid(label: &str) -> Option<&'static str> {
+    match label {
+        BOOT_LABEL => Some(BOOT_TYPE_GUID),
+        RW_LABEL => Some(RW_TYPE_GUID),
+        l if is_root_label(l) => Some(ROOT_TYPE_GUID),
+        _ => None,
+    }
+}
+
+// Returns the disk holding the booted root and its root slot partitions, ordered by label.
+// With no slot mounted as root (ex: installing, or a disk image) there must be only one disk
+// with root slots. Root slot labels appearing on more than one disk are an error, since the
+// wrong disk could be overwritten. Whether slots must have the root type is decided per disk.
+pub fn find_root_parts(log: &Logger, host: &Host) -> Result<(LsblkDevice, Vec<LsblkDevice>)> {
+    // Disk, whether it has typed root slots, and its root slots
+    let mut candidates = vec![];
+    for lsblk_parent in block_devices(host)? {
+        if !lsblk_parent.is_disk() {
             continue;
         }
+        // Once a partition on the disk has the root type, its untyped ones are never root slots
+        let typed = lsblk_parent
+            .children
+            .iter()
+            .any(|p| p.parttype.as_deref() == Some(ROOT_TYPE_GUID));
+        let mut out = vec![];
         for part in &lsblk_parent.children {
             let label = match &part.partlabel {
                 Some(l) => l,
@@ -110,7 +234,7 @@ pub fn find_root_parts(log: &Logger) -> Result<(LsblkDevice, [LsblkDevice; 2])>
                     continue;
                 }
             };
-            if !ROOT_LABELS.iter().any(|l| *l == label) {
+            if !is_root_label(label) {
                 trace!(
                     log,
                     "Device has unknown gpt label, skipping",
@@ -119,30 +243,91 @@ pub fn find_root_parts(log: &Logger) -> Result<(LsblkDevice, [LsblkDevice; 2])>
                 );
                 continue;
             }
+            if typed && part.parttype.as_deref() != Some(ROOT_TYPE_GUID) {
+                trace!(
+                    log,
+                    "Device has root slot label but not the root partition type, skipping",
+                    dev = &part.path,
+                    label = &label
+                );
+                continue;
+            }
             out.push(part.clone());
         }
-        if out.len() == 2 {
-            return Ok((lsblk_parent, [out.remove(0), out.remove(0)]));
+        if !out.is_empty() {
+            candidates.push((lsblk_parent, typed, out));
+        }
+    }
+    // Disks of the other kind than the booted one (or typed ones if none is booted) belong to
+    // something else, ex: an old untyped disk next to a typed install, or a typed usb stick
+    // plugged into an untyped install
+    let booted = |parts: &[LsblkDevice]| parts.iter().any(|p| p.mountpoint.as_deref() == Some("/"));
+    let typed = match candidates.iter().find(|c| booted(&c.2)) {
+        Some(c) => c.1,
+        None => candidates.iter().any(|c| c.1),
+    };
+    for (disk, _, _) in candidates.iter().filter(|c| c.1 != typed) {
+        trace!(
+            log,
+            "Ignoring root slots on disk of the other kind",
+            dev = &disk.path
+        );
+    }
+    candidates.retain(|c| c.1 == typed);
+    let mut seen: HashMap<&str, &str> = HashMap::new();
+    for part in candidates.iter().flat_map(|c| &c.2) {
+        let label = part.partlabel.as_deref().unwrap();
+        if let Some(other) = seen.insert(label, &part.path) {
+            return Err(anyhow!(
+                "Root slot label {} is used by both {} and {}, refusing to pick one",
+                label,
+                other,
+                part.path
+            ));
+        }
+    }
+    let (disk, _, mut out) = match candidates.iter().position(|c| booted(&c.2)) {
+        Some(i) => candidates.swap_remove(i),
+        None if candidates.len() > 1 => {
+            return Err(anyhow!(
+                "None of the disks with root slots ({}) holds the booted root",
+                candidates
+                    .iter()
+                    .map(|c| c.0.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
         }
+        None => match candidates.pop() {
+            Some(c) => c,
+            None => return Err(anyhow!("Found no root partitions on any disk")),
+        },
+    };
+    if out.len() < 2 {
+        return Err(anyhow!(
+            "Expected to find at least 2 root partitions on {}, but found {}",
+            disk.path,
+            out.len()
+        ));
     }
-    return Err(anyhow!(
-        "Expected to find {} root partitions, but found {} (or they were on separate disks)",
-        ROOT_LABELS.len(),
-        out.len()
-    ));
+    out.sort_by(|a, b| a.partlabel.cmp(&b.partlabel));
+    Ok((disk, out))
 }
 
 pub struct Mount {
     log: Logger,
+    runner: Arc<dyn Runner>,
     dest: PathBuf,
 }
 
 impl Mount {
-    pub fn new(log: Logger, source: &Path, dest: &Path) -> Result<Mount> {
-        let mount_out = Command::new("mount")
-            .arg(source.as_os_str())
-            .arg(dest.as_os_str())
-            .output()
+    pub fn new(log: Logger, runner: Arc<dyn Runner>, source: &Path, dest: &Path) -> Result<Mount> {
+        let mount_out = runner
+            .output(
+                Command::new("mount")
+                    .arg(source.as_os_str())
+                    .arg(dest.as_os_str()),
+            )
             .map_err(|e| {
                 anyhow!(
                     "Failed to run mount of {} to {}",
@@ -161,6 +346,7 @@ impl Mount {
         }
         Ok(Mount {
             log: log,
+            runner: runner,
             dest: dest.to_path_buf(),
         })
     }
@@ -168,7 +354,10 @@ impl Mount {
 
 impl Drop for Mount {
     fn drop(&mut self) {
-        if let Err(e) = Command::new("umount").arg(self.dest.as_os_str()).run() {
+        if let Err(e) = Command::new("umount")
+            .arg(self.dest.as_os_str())
+            .run(&*self.runner)
+        {
             warn!(
                 self.log,
                 "Failed to unmount",
@@ -180,78 +369,469 @@ impl Drop for Mount {
     }
 }
 
-pub fn mount_boot(log: Logger) -> Result<Mount> {
+pub fn mount_boot(log: Logger, host: &Host) -> Result<Mount> {
     Mount::new(
         log.clone(),
-        Path::new(&format!("/dev/disk/by-partlabel/{}", BOOT_LABEL)),
-        Path::new("/boot"),
+        host.runner.clone(),
+        &host.part_path(BOOT_LABEL)?,
+        &host.boot_dir,
     )
 }
 
-#[derive(Deserialize, Serialize)]
+fn default_boot_attempts() -> u32 {
+    3
+}
+
+fn default_watchdog_deadline() -> u64 {
+    900
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct InternalMeta {
     // AWS region or custom endpoint
     pub region: String,
     pub bucket: String,
     pub object_path: String,
+    // Static keys, only used if `credentials` isn't set
+    #[serde(default)]
     pub access_key: String,
+    #[serde(default)]
     pub secret_key: String,
+    // Where to get credentials for pulling new versions
+    #[serde(default)]
+    pub credentials: Option<CredentialSource>,
     pub uuid: String,
     pub der_bzimage: String,
     pub der_init: String,
     pub der_initrd: String,
+    // Number of times to try booting this version before falling back
+    #[serde(default = "default_boot_attempts")]
+    pub boot_attempts: u32,
+    // Checks that must pass before the boot is marked successful
+    #[serde(default)]
+    pub health: HealthChecks,
+    // Seconds after boot to wait for the boot to be marked successful before rolling back
+    #[serde(default = "default_watchdog_deadline")]
+    pub watchdog_deadline: u64,
+    // Only consider the network ready once the update endpoint resolves and accepts a tcp
+    // connection, rather than as soon as there's a default route
+    #[serde(default)]
+    pub check_endpoint: bool,
+    // Proxy and TLS settings for downloading updates
+    #[serde(default)]
+    pub http: HttpConfig,
+    // Unix seconds, the clock is considered wrong if it's earlier than this
+    #[serde(default)]
+    pub build_time: Option<i64>,
+    #[serde(default)]
+    pub clock: ClockConfig,
+    // Other buckets the version is replicated to, tried after the version's own bucket
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    // Take a lease in the bucket before rebooting into a new version, limiting how many devices
+    // in a group reboot at once
+    #[serde(default)]
+    pub reboot_lease: Option<LeaseConfig>,
+    // Local socket for applications to follow and drive updates
+    #[serde(default)]
+    pub control: Option<ControlConfig>,
+    // Object paths other versions are published to, by name. A device follows one by setting
+    // `channel` in its runtime config.
+    #[serde(default)]
+    pub channels: HashMap<String, String>,
+    #[serde(default)]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub schedule: Schedule,
+    // Don't install or activate new versions
+    #[serde(default)]
+    pub hold: bool,
+    // Poll the bucket for directives to this device
+    #[serde(default)]
+    pub directives: Option<DirectivesConfig>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ExternalMeta {
     pub sha256: String,
     pub size: u64,
     pub format: String,
     pub internal: InternalMeta,
+    // Set when uploaded, mirrors with an older meta haven't caught up yet
+    #[serde(default)]
+    pub published: Option<DateTime<Utc>>,
 }
 
 pub fn current_meta() -> Result<InternalMeta> {
     Ok(
-        serde_json::from_slice(&read_bytes(Path::new("/organixm.json"))?)
-            .context("Failed to parse current system meta")?,
+        serde_json::from_slice(
+            &read_bytes(Path::new("/organixm.json")).context(ErrorKind::Config)?,
+        )
+        .context("Failed to parse current system meta")
+        .context(ErrorKind::Config)?,
+    )
+}
+
+// Requires /boot to be mounted
+pub fn grub_env_list(host: &Host) -> Result<HashMap<String, String>> {
+    let output = host
+        .runner
+        .output(
+            Command::new("grub-editenv")
+                .arg(host.grub_env_path())
+                .arg("list"),
+        )
+        .context("Failed to run grub-editenv list")?;
+    if !output.status.success() {
+        return Err(anyhow!("grub-editenv list failed: {:?}", output));
+    }
+    let mut out = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some((k, v)) = line.split_once('=') {
+            out.insert(k.to_string(), v.to_string());
+        }
+    }
+    Ok(out)
+}
+
+// Requires /boot to be mounted
+pub fn grub_env_set(host: &Host, vars: &[(&str, &str)]) -> Result<()> {
+    let mut c = Command::new("grub-editenv");
+    c.arg(host.grub_env_path()).arg("set");
+    for (k, v) in vars {
+        c.arg(format!("{}={}", k, v));
+    }
+    c.run(&*host.runner)
+}
+
+// Requires /boot to be mounted
+pub fn grub_env_unset(host: &Host, vars: &[&str]) -> Result<()> {
+    Command::new("grub-editenv")
+        .arg(host.grub_env_path())
+        .arg("unset")
+        .args(vars)
+        .run(&*host.runner)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SlotState {
+    pub label: String,
+    pub meta: InternalMeta,
+    pub installed: DateTime<Utc>,
+}
+
+// Requires /boot to be mounted. Missing for installs from before slot tracking.
+pub fn read_slots(host: &Host) -> Result<Vec<SlotState>> {
+    let path = host.slots_path();
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse slot state")?)
+}
+
+// Requires /boot to be mounted
+pub fn write_slots(host: &Host, slots: &[SlotState]) -> Result<()> {
+    let path = host.slots_path();
+    ec!(("Writing slot state to {}", path.to_string_lossy()), {
+        File::create(&path)
+            .context("Failed to open for writing")?
+            .write_all(&serde_json::to_vec_pretty(slots).unwrap())
+            .context("Failed to write")?;
+        Ok(())
+    })
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FailedVersion {
+    pub uuid: String,
+    pub detected: DateTime<Utc>,
+}
+
+fn failed_versions_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("failed.json")
+}
+
+// Versions that failed to boot, never installed again automatically
+pub fn read_failed_versions(state_dir: &Path) -> Result<Vec<FailedVersion>> {
+    let path = failed_versions_path(state_dir);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    Ok(serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse failed versions")?)
+}
+
+pub fn record_failed_version(state_dir: &Path, uuid: &str) -> Result<()> {
+    let path = failed_versions_path(state_dir);
+    ec!(
+        ("Recording failed version in {}", path.to_string_lossy()),
+        {
+            let mut failed = read_failed_versions(state_dir)?;
+            if failed.iter().any(|f| f.uuid == uuid) {
+                return Ok(());
+            }
+            failed.push(FailedVersion {
+                uuid: uuid.to_string(),
+                detected: Utc::now(),
+            });
+            create_dir_all(state_dir).context("Failed to create state dir")?;
+            File::create(&path)
+                .context("Failed to open for writing")?
+                .write_all(&serde_json::to_vec_pretty(&failed).unwrap())
+                .context("Failed to write")?;
+            Ok(())
+        }
+    )
+}
+
+// The last fetched meta, for conditional fetches and showing the latest known version offline
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MetaCache {
+    pub etag: Option<String>,
+    pub meta: ExternalMeta,
+    pub fetched: DateTime<Utc>,
+}
+
+fn meta_cache_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("meta.json")
+}
+
+pub fn read_meta_cache(state_dir: &Path) -> Result<Option<MetaCache>> {
+    let path = meta_cache_path(state_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse cached meta")?,
+    ))
+}
+
+pub fn write_meta_cache(state_dir: &Path, cache: &MetaCache) -> Result<()> {
+    let path = meta_cache_path(state_dir);
+    ec!(("Caching meta in {}", path.to_string_lossy()), {
+        create_dir_all(state_dir).context("Failed to create state dir")?;
+        File::create(&path)
+            .context("Failed to open for writing")?
+            .write_all(&serde_json::to_vec_pretty(cache).unwrap())
+            .context("Failed to write")?;
+        Ok(())
+    })
+}
+
+// A version written and verified in a slot but not activated yet
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StagedVersion {
+    pub meta: ExternalMeta,
+    // Partition label of the slot
+    pub label: String,
+    pub staged: DateTime<Utc>,
+}
+
+fn staged_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("staged.json")
+}
+
+pub fn read_staged(state_dir: &Path) -> Result<Option<StagedVersion>> {
+    let path = staged_path(state_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(
+        serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse staged version")?,
+    ))
+}
+
+pub fn write_staged(state_dir: &Path, staged: &StagedVersion) -> Result<()> {
+    let path = staged_path(state_dir);
+    ec!(
+        ("Recording staged version in {}", path.to_string_lossy()),
+        {
+            create_dir_all(state_dir).context("Failed to create state dir")?;
+            File::create(&path)
+                .context("Failed to open for writing")?
+                .write_all(&serde_json::to_vec_pretty(staged).unwrap())
+                .context("Failed to write")?;
+            Ok(())
+        }
     )
 }
 
+pub fn clear_staged(state_dir: &Path) -> Result<()> {
+    let path = staged_path(state_dir);
+    ec!(("Clearing staged version in {}", path.to_string_lossy()), {
+        if path.exists() {
+            remove_file(&path).context("Failed to remove")?;
+        }
+        Ok(())
+    })
+}
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    // Keep retrying until this much time has passed (at least 2 attempts are made)
+    pub total_time: Duration,
+    // Delay after the first failure
+    pub initial: Duration,
+    // Delay is multiplied by this after each failure
+    pub multiplier: f64,
+    // Upper bound on delay
+    pub max: Duration,
+    // Fraction (0-1) of each delay to randomly subtract, to avoid many devices retrying in lockstep
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn fixed(total_time: Duration, period: Duration) -> RetryPolicy {
+        RetryPolicy {
+            total_time: total_time,
+            initial: period,
+            multiplier: 1.,
+            max: period,
+            jitter: 0.,
+        }
+    }
+
+    fn delay(&self, attempt: i32) -> Duration {
+        let base = (self.initial.num_milliseconds() as f64 * self.multiplier.powi(attempt))
+            .min(self.max.num_milliseconds() as f64);
+        Duration::milliseconds((base * (1. - self.jitter * fastrand::f64())) as i64)
+    }
+}
+
+// Failure categories, added as context to errors. The outermost kind in an error chain
+// determines the process exit code, unclassified errors exit with 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The current version meta or a config file couldn't be read or parsed
+    Config,
+    // No route to the internet or the update server couldn't be reached
+    Network,
+    // The update server rejected the credentials
+    Credentials,
+    // The update server responded with something unexpected, like a missing or invalid meta
+    Server,
+    // The downloaded image didn't match the published digest
+    Verification,
+    // Disk discovery, partitioning, or writing to disk failed
+    Disk,
+    // Updating the grub config or env failed
+    Bootloader,
+    // Health checks didn't pass before the deadline
+    Health,
+    // No reboot lease was free before giving up
+    Lease,
+}
+
+impl ErrorKind {
+    pub fn of(e: &anyhow::Error) -> Option<ErrorKind> {
+        e.downcast_ref::<ErrorKind>().copied()
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Config => 2,
+            ErrorKind::Network => 3,
+            ErrorKind::Credentials => 4,
+            ErrorKind::Server => 5,
+            ErrorKind::Verification => 6,
+            ErrorKind::Disk => 7,
+            ErrorKind::Bootloader => 8,
+            ErrorKind::Health => 9,
+            ErrorKind::Lease => 10,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Config => "config",
+            ErrorKind::Network => "network",
+            ErrorKind::Credentials => "credentials",
+            ErrorKind::Server => "server",
+            ErrorKind::Verification => "verification",
+            ErrorKind::Disk => "disk",
+            ErrorKind::Bootloader => "bootloader",
+            ErrorKind::Health => "health",
+            ErrorKind::Lease => "lease",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failure category: {}", self.name())
+    }
+}
+
+// Exit code for an error returned from a binary's main
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    ErrorKind::of(e).map(|k| k.exit_code()).unwrap_or(1)
+}
+
+// Add as context to an error to stop `retry` immediately
+#[derive(Debug)]
+pub struct Permanent;
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Error is permanent, not retrying")
+    }
+}
+
 pub fn retry<R, F: FnMut() -> Result<R>>(
     log: &Logger,
-    total_time: Duration,
-    period: Duration,
+    policy: &RetryPolicy,
     mut f: F,
 ) -> Result<R> {
     let start = Utc::now();
     let mut count = 0;
     loop {
-        count += 1;
         let e = match f() {
             Ok(r) => {
                 return Ok(r);
             }
             Err(e) => e,
         };
+        count += 1;
+        if e.downcast_ref::<Permanent>().is_some() {
+            return Err(e);
+        }
         let now = Utc::now();
         let elapsed = now - start;
-        if elapsed >= total_time && count >= 2 {
-            return Err(
-                anyhow!("Giving up after {} ({} attempts)", elapsed.hhmmss(), count).context(e),
-            );
+        if elapsed >= policy.total_time && count >= 2 {
+            return Err(e.context(format!(
+                "Giving up after {} ({} attempts)",
+                elapsed.hhmmss(),
+                count
+            )));
         }
-        trace!(log, "Retry attempt failed", err = format!("{:?}", e));
-        thread::sleep(period.to_std().unwrap());
+        let delay = policy.delay(count - 1);
+        trace!(
+            log,
+            "Retry attempt failed",
+            err = format!("{:?}", e),
+            delay = delay.hhmmss()
+        );
+        thread::sleep(delay.to_std().unwrap());
+    }
+}
+
+// Credential and permission errors won't go away by retrying
+pub fn check_s3_status(code: u16) -> Result<()> {
+    match code {
+        200..=299 => Ok(()),
+        401 | 403 => Err(anyhow!("Server rejected credentials (status {})", code)
+            .context(ErrorKind::Credentials)
+            .context(Permanent)),
+        _ => Err(anyhow!("Server responded with status {}", code).context(ErrorKind::Server)),
     }
 }
 
 pub trait SimpleCommand {
-    fn run(&mut self) -> Result<()>;
+    fn run(&mut self, runner: &dyn Runner) -> Result<()>;
 }
 
 impl SimpleCommand for Command {
-    fn run(&mut self) -> Result<()> {
-        match match self.output() {
+    fn run(&mut self, runner: &dyn Runner) -> Result<()> {
+        match match runner.output(self) {
             Ok(o) => {
                 if o.status.success() {
                     Ok(())
@@ -268,25 +848,41 @@ impl SimpleCommand for Command {
 }
 
 pub fn file_digest(path: &Path, size: u64) -> Result<String> {
-    let mut other_digest = sha2::Sha256::new();
-    copy_finish(&mut File::open(path)?.take(size), &mut other_digest)?;
-    Ok(format!("{:x}", other_digest.finalize()))
-}
-
-pub fn version_bucket(version: &InternalMeta) -> Result<Bucket> {
-    let mut bucket = Bucket::new(
-        &version.bucket,
-        s3::Region::from_str(&version.region)?,
-        Credentials {
-            access_key: Some(version.access_key.clone()),
-            secret_key: Some(version.secret_key.clone()),
-            security_token: None,
-            session_token: None,
-            expiration: None,
-        },
-    )?;
-    bucket.set_request_timeout(Some(Duration::minutes(60).to_std().unwrap()));
-    Ok(bucket)
+    read_digest(&mut File::open(path)?, size)
+}
+
+pub fn read_digest<R: Read + ?Sized>(reader: &mut R, size: u64) -> Result<String> {
+    let mut digest = sha2::Sha256::new();
+    copy_finish(&mut reader.take(size), &mut digest)?;
+    Ok(format!("{:x}", digest.finalize()))
+}
+
+// Presigned url for `key` in the version's bucket, signed at `clock::now()`
+pub fn presign_url(
+    version: &InternalMeta,
+    credentials: Credentials,
+    method: &str,
+    key: &str,
+    expiry_secs: u32,
+) -> Result<String> {
+    let presigner = Presigner::for_version(version, credentials)
+        .context("Failed to sign request")
+        .context(ErrorKind::Config)?;
+    Ok(presigner.url(method, key, expiry_secs, clock::now()))
+}
+
+// Host and port of the update server, for reachability checks
+pub fn version_endpoint(version: &InternalMeta) -> Result<String> {
+    let region = s3::Region::from_str(&version.region)?;
+    let host = region.host();
+    if host.contains(':') {
+        return Ok(host);
+    }
+    Ok(format!(
+        "{}:{}",
+        host,
+        if region.scheme() == "http" { 80 } else { 443 }
+    ))
 }
 
 pub struct ErrCtx<'a>(pub fmt::Arguments<'a>);
@@ -331,3 +927,138 @@ where
     writer.flush()?;
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::o;
+
+    pub fn logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    pub fn meta(uuid: &str) -> InternalMeta {
+        serde_json::from_value(serde_json::json!({
+            "region": "us-east-1",
+            "bucket": "versions",
+            "object_path": "system",
+            "access_key": "key",
+            "secret_key": "secret",
+            "uuid": uuid,
+            "der_bzimage": format!("/nix/store/{}/bzImage", uuid),
+            "der_init": format!("/nix/store/{}/init", uuid),
+            "der_initrd": format!("/nix/store/{}/initrd", uuid),
+        }))
+        .unwrap()
+    }
+
+    fn policy(total_ms: i64, initial_ms: i64, multiplier: f64, max_ms: i64) -> RetryPolicy {
+        RetryPolicy {
+            total_time: Duration::milliseconds(total_ms),
+            initial: Duration::milliseconds(initial_ms),
+            multiplier: multiplier,
+            max: Duration::milliseconds(max_ms),
+            jitter: 0.,
+        }
+    }
+
+    #[test]
+    fn error_kinds_map_to_exit_codes() {
+        let table = [
+            (ErrorKind::Config, 2),
+            (ErrorKind::Network, 3),
+            (ErrorKind::Credentials, 4),
+            (ErrorKind::Server, 5),
+            (ErrorKind::Verification, 6),
+            (ErrorKind::Disk, 7),
+            (ErrorKind::Bootloader, 8),
+            (ErrorKind::Health, 9),
+            (ErrorKind::Lease, 10),
+        ];
+        for (kind, code) in table {
+            let e = anyhow!("Failed").context(kind).context("While updating");
+            assert_eq!(exit_code(&e), code, "{}", kind.name());
+        }
+        assert_eq!(exit_code(&anyhow!("Failed")), 1);
+        // The outermost kind wins
+        let e = anyhow!("Failed")
+            .context(ErrorKind::Network)
+            .context(ErrorKind::Health);
+        assert_eq!(exit_code(&e), 9);
+    }
+
+    #[test]
+    fn retry_delay_backs_off_up_to_max() {
+        let delays = |p: &RetryPolicy| {
+            (0..5)
+                .map(|a| p.delay(a).num_milliseconds())
+                .collect::<Vec<_>>()
+        };
+        let mut p = policy(0, 100, 2., 500);
+        assert_eq!(delays(&p), vec![100, 200, 400, 500, 500]);
+        assert_eq!(
+            delays(&RetryPolicy::fixed(
+                Duration::seconds(1),
+                Duration::milliseconds(30)
+            )),
+            vec![30; 5]
+        );
+        // Jitter only ever shortens the delay
+        p.jitter = 0.5;
+        for _ in 0..20 {
+            let d = p.delay(2).num_milliseconds();
+            assert!((200..=400).contains(&d), "{}", d);
+        }
+    }
+
+    #[test]
+    fn retry_returns_first_success() {
+        let mut calls = 0;
+        let r = retry(&logger(), &policy(1000, 1, 1., 1), || {
+            calls += 1;
+            match calls {
+                3 => Ok(calls),
+                _ => Err(anyhow!("Not yet")),
+            }
+        })
+        .unwrap();
+        assert_eq!(r, 3);
+    }
+
+    #[test]
+    fn retry_gives_up_when_budget_is_spent() {
+        let start = Utc::now();
+        let mut calls = 0;
+        let e = retry(&logger(), &policy(50, 10, 1., 10), || -> Result<()> {
+            calls += 1;
+            Err(anyhow!("Down").context(ErrorKind::Network))
+        })
+        .unwrap_err();
+        assert!(Utc::now() - start >= Duration::milliseconds(50));
+        assert!(calls >= 2);
+        assert!(e.to_string().ends_with(&format!("({} attempts)", calls)));
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Network));
+
+        // At least 2 attempts even with no budget
+        let mut calls = 0;
+        retry(&logger(), &policy(0, 0, 1., 0), || -> Result<()> {
+            calls += 1;
+            Err(anyhow!("Down"))
+        })
+        .unwrap_err();
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let mut calls = 0;
+        let e = retry(&logger(), &policy(1000, 1, 1., 1), || -> Result<()> {
+            calls += 1;
+            check_s3_status(403)
+        })
+        .unwrap_err();
+        assert_eq!(calls, 1);
+        assert!(e.downcast_ref::<Permanent>().is_some());
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Credentials));
+    }
+}
@@ -0,0 +1,590 @@
+// Failover between several places a version is published, in priority order with mirrors that
+// failed recently tried last
+use crate::{
+    credentials::CredentialSource,
+    ec, info, read_bytes,
+    updater::{MetaFetch, Source},
+    warn, ErrorKind, ExternalMeta, InternalMeta, Permanent,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Mirror {
+    pub region: String,
+    pub bucket: String,
+    // Defaults to the version's object path
+    #[serde(default)]
+    pub object_path: Option<String>,
+    // Lower is tried first, the version's own bucket has priority 0
+    #[serde(default)]
+    pub priority: i32,
+    // Defaults to the version's credentials
+    #[serde(default)]
+    pub credentials: Option<CredentialSource>,
+}
+
+impl Mirror {
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.region, self.bucket)
+    }
+
+    // The version meta pointed at this mirror
+    pub fn apply(&self, meta: &InternalMeta) -> InternalMeta {
+        let mut out = meta.clone();
+        out.region = self.region.clone();
+        out.bucket = self.bucket.clone();
+        if let Some(p) = &self.object_path {
+            out.object_path = p.clone();
+        }
+        if let Some(c) = &self.credentials {
+            out.credentials = Some(c.clone());
+        }
+        out
+    }
+}
+
+// Mirrors that failed within this long are tried after the others
+const UNHEALTHY_FOR_MINUTES: i64 = 60;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MirrorHealth {
+    // Consecutive failures
+    pub failures: u32,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    // Of the last meta fetched from the mirror, only sent back to the same mirror
+    #[serde(default)]
+    pub etag: Option<String>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct MirrorState {
+    pub mirrors: HashMap<String, MirrorHealth>,
+    // Newest publish time seen from any mirror, older metas are stale
+    pub newest_published: Option<DateTime<Utc>>,
+}
+
+fn mirror_state_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("mirrors.json")
+}
+
+pub fn read_mirror_state(state_dir: &Path) -> Result<MirrorState> {
+    let path = mirror_state_path(state_dir);
+    if !path.exists() {
+        return Ok(MirrorState::default());
+    }
+    Ok(serde_json::from_slice(&read_bytes(&path)?).context("Failed to parse mirror state")?)
+}
+
+fn write_mirror_state(state_dir: &Path, state: &MirrorState) -> Result<()> {
+    let path = mirror_state_path(state_dir);
+    ec!(("Writing mirror state to {}", path.to_string_lossy()), {
+        create_dir_all(state_dir).context("Failed to create state dir")?;
+        File::create(&path)
+            .context("Failed to open for writing")?
+            .write_all(&serde_json::to_vec_pretty(state).unwrap())
+            .context("Failed to write")?;
+        Ok(())
+    })
+}
+
+// The error for every mirror failing, with each mirror's error in the message. It's caused by the
+// first mirror's error with a more specific kind than network if any, so it keeps that kind, and
+// is only permanent if every mirror's error is.
+fn all_failed(errors: Vec<(String, anyhow::Error)>) -> anyhow::Error {
+    let summary = errors
+        .iter()
+        .map(|(name, e)| format!("{}: {:#}", name, e))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let permanent = |e: &anyhow::Error| e.downcast_ref::<Permanent>().is_some();
+    let all_permanent = errors.iter().all(|(_, e)| permanent(e));
+    let mut candidates = errors
+        .into_iter()
+        .map(|(_, e)| e)
+        .filter(|e| all_permanent || !permanent(e))
+        .collect::<Vec<_>>();
+    let specific = candidates
+        .iter()
+        .position(|e| !matches!(ErrorKind::of(e), None | Some(ErrorKind::Network)));
+    if candidates.is_empty() {
+        return anyhow!("No mirrors to fetch from").context(ErrorKind::Config);
+    }
+    let cause = candidates.remove(specific.unwrap_or(0));
+    let kind = ErrorKind::of(&cause);
+    let e = cause.context(format!("No mirror could be reached: {}", summary));
+    match kind {
+        Some(_) => e,
+        None => e.context(ErrorKind::Network),
+    }
+}
+
+pub struct MirrorSource {
+    log: Logger,
+    state_dir: PathBuf,
+    // Name, priority, source
+    mirrors: Vec<(String, i32, Box<dyn Source>)>,
+    state: Mutex<MirrorState>,
+    // The mirror the last meta came from
+    meta_mirror: Mutex<Option<usize>>,
+    // Mirror to download the image from next, starting with the one the meta came from
+    image_order: Mutex<Vec<usize>>,
+}
+
+impl MirrorSource {
+    pub fn new(
+        log: Logger,
+        state_dir: PathBuf,
+        mirrors: Vec<(String, i32, Box<dyn Source>)>,
+    ) -> MirrorSource {
+        let state = match read_mirror_state(&state_dir) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(log, "Failed to read mirror state", err = format!("{:?}", e));
+                MirrorState::default()
+            }
+        };
+        MirrorSource {
+            log: log,
+            state_dir: state_dir,
+            mirrors: mirrors,
+            state: Mutex::new(state),
+            meta_mirror: Mutex::new(None),
+            image_order: Mutex::new(vec![]),
+        }
+    }
+
+    // Indexes of healthy mirrors by priority, then unhealthy ones by priority
+    fn order(&self) -> Vec<usize> {
+        let state = self.state.lock().unwrap();
+        let cutoff = Utc::now() - Duration::minutes(UNHEALTHY_FOR_MINUTES);
+        let mut order = (0..self.mirrors.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| {
+            let (name, priority, _) = &self.mirrors[*i];
+            let unhealthy = state
+                .mirrors
+                .get(name)
+                .map(|h| h.failures > 0 && h.last_failure.map(|t| t > cutoff).unwrap_or(false))
+                .unwrap_or(false);
+            (unhealthy, *priority)
+        });
+        order
+    }
+
+    // `etag` if it came from the mirror
+    fn etag_for<'a>(&self, index: usize, etag: Option<&'a str>) -> Option<&'a str> {
+        let state = self.state.lock().unwrap();
+        let own = state
+            .mirrors
+            .get(&self.mirrors[index].0)
+            .and_then(|h| h.etag.as_deref());
+        etag.filter(|e| Some(*e) == own)
+    }
+
+    fn set_etag(&self, index: usize, etag: &Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .mirrors
+            .entry(self.mirrors[index].0.clone())
+            .or_default()
+            .etag = etag.clone();
+    }
+
+    fn record(&self, index: usize, result: Result<(), String>) {
+        let mut state = self.state.lock().unwrap();
+        let health = state
+            .mirrors
+            .entry(self.mirrors[index].0.clone())
+            .or_default();
+        match result {
+            Ok(()) => {
+                health.failures = 0;
+                health.last_success = Some(Utc::now());
+            }
+            Err(e) => {
+                warn!(
+                    self.log,
+                    "Mirror failed",
+                    mirror = &self.mirrors[index].0,
+                    err = &e
+                );
+                health.failures += 1;
+                health.last_failure = Some(Utc::now());
+                health.last_error = Some(e);
+            }
+        }
+        self.save(&state);
+    }
+
+    fn save(&self, state: &MirrorState) {
+        if let Err(e) = write_mirror_state(&self.state_dir, state) {
+            warn!(
+                self.log,
+                "Failed to save mirror state",
+                err = format!("{:?}", e)
+            );
+        }
+    }
+}
+
+impl Source for MirrorSource {
+    fn ready(&self) -> Result<bool> {
+        for i in self.order() {
+            if self.mirrors[i].2.ready()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+        let newest = self.state.lock().unwrap().newest_published;
+        let order = self.order();
+        let mut errors: Vec<(String, anyhow::Error)> = vec![];
+        // The freshest of the stale metas, used only if no mirror is up to date
+        let mut stale: Option<(usize, Box<ExternalMeta>, Option<String>)> = None;
+        for (n, i) in order.iter().enumerate() {
+            let name = &self.mirrors[*i].0;
+            match self.mirrors[*i].2.fetch_meta(self.etag_for(*i, etag)) {
+                Err(e) => {
+                    self.record(*i, Err(format!("{:#}", e)));
+                    errors.push((name.clone(), e));
+                }
+                Ok(MetaFetch::Fetched(meta, tag))
+                    if meta.published.is_some() && meta.published < newest =>
+                {
+                    self.record(
+                        *i,
+                        Err(format!(
+                            "Stale meta published {}, newest seen {}",
+                            meta.published.unwrap(),
+                            newest.unwrap()
+                        )),
+                    );
+                    if stale.as_ref().map(|s| s.1.published < meta.published) != Some(false) {
+                        stale = Some((*i, meta, tag));
+                    }
+                }
+                Ok(fetched) => {
+                    if let MetaFetch::Fetched(meta, tag) = &fetched {
+                        if meta.published > newest {
+                            self.state.lock().unwrap().newest_published = meta.published;
+                        }
+                        self.set_etag(*i, tag);
+                    }
+                    // Saves the newest publish time and etag too
+                    self.record(*i, Ok(()));
+                    info!(self.log, "Using mirror", mirror = name);
+                    let mut image_order = order.clone();
+                    image_order.rotate_left(n);
+                    *self.image_order.lock().unwrap() = image_order;
+                    *self.meta_mirror.lock().unwrap() = Some(*i);
+                    return Ok(fetched);
+                }
+            }
+        }
+        if let Some((i, meta, tag)) = stale {
+            warn!(
+                self.log,
+                "All reachable mirrors have stale metas, using the newest",
+                mirror = &self.mirrors[i].0
+            );
+            let mut image_order = order.clone();
+            image_order.retain(|o| *o != i);
+            image_order.insert(0, i);
+            *self.image_order.lock().unwrap() = image_order;
+            *self.meta_mirror.lock().unwrap() = Some(i);
+            self.set_etag(i, &tag);
+            self.save(&self.state.lock().unwrap());
+            return Ok(MetaFetch::Fetched(meta, tag));
+        }
+        Err(all_failed(errors))
+    }
+
+    // Tries one mirror per call, moving to the next one after a failure since the destination
+    // can't be reused
+    fn fetch_image(&self, meta: &ExternalMeta, dest: &mut (dyn Write + Send)) -> Result<()> {
+        let mut image_order = self.image_order.lock().unwrap();
+        if image_order.is_empty() {
+            *image_order = self.order();
+        }
+        let i = image_order[0];
+        let (name, _, source) = &self.mirrors[i];
+        let result = (|| {
+            // Only the mirror the meta came from is known to have this version, others must
+            // publish the same digest or the image would fail verification after downloading
+            if Some(i) != *self.meta_mirror.lock().unwrap() {
+                match source.fetch_meta(None)? {
+                    MetaFetch::Fetched(m, _)
+                        if m.internal.uuid == meta.internal.uuid
+                            && m.sha256 == meta.sha256
+                            && m.size == meta.size => {}
+                    MetaFetch::Fetched(m, _) => {
+                        return Err(anyhow!(
+                            "Mirror has version {} with digest {}, expected {} with digest {}",
+                            m.internal.uuid,
+                            m.sha256,
+                            meta.internal.uuid,
+                            meta.sha256
+                        )
+                        .context(ErrorKind::Server))
+                    }
+                    MetaFetch::NotModified => {
+                        return Err(anyhow!("Mirror responded not modified without an etag")
+                            .context(ErrorKind::Server))
+                    }
+                }
+            }
+            info!(self.log, "Downloading image from mirror", mirror = name);
+            source.fetch_image(meta, dest)
+        })();
+        match result {
+            Ok(()) => {
+                self.record(i, Ok(()));
+                Ok(())
+            }
+            Err(e) => {
+                self.record(i, Err(format!("{:#}", e)));
+                image_order.rotate_left(1);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        check_s3_status,
+        tests::{logger, meta},
+    };
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn external(uuid: &str) -> ExternalMeta {
+        ExternalMeta {
+            sha256: "abcd".to_string(),
+            size: 4,
+            format: "zstd".to_string(),
+            internal: meta(uuid),
+            published: None,
+        }
+    }
+
+    // Serves a meta with its own etag, recording the etag sent with each fetch
+    struct Tagged {
+        etag: &'static str,
+        sent: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Tagged {
+        fn new(etag: &'static str) -> (Tagged, Arc<Mutex<Vec<Option<String>>>>) {
+            let sent = Arc::new(Mutex::new(vec![]));
+            (
+                Tagged {
+                    etag: etag,
+                    sent: sent.clone(),
+                },
+                sent,
+            )
+        }
+    }
+
+    impl Source for Tagged {
+        fn ready(&self) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+            self.sent.lock().unwrap().push(etag.map(|e| e.to_string()));
+            if etag == Some(self.etag) {
+                return Ok(MetaFetch::NotModified);
+            }
+            Ok(MetaFetch::Fetched(
+                Box::new(external("new")),
+                Some(self.etag.to_string()),
+            ))
+        }
+
+        fn fetch_image(&self, _meta: &ExternalMeta, _dest: &mut (dyn Write + Send)) -> Result<()> {
+            Err(anyhow!("Connection reset").context(ErrorKind::Network))
+        }
+    }
+
+    // Fails every request with the error from `error`
+    struct Failing(fn() -> anyhow::Error);
+
+    impl Source for Failing {
+        fn ready(&self) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn fetch_meta(&self, _etag: Option<&str>) -> Result<MetaFetch> {
+            Err((self.0)())
+        }
+
+        fn fetch_image(&self, _meta: &ExternalMeta, _dest: &mut (dyn Write + Send)) -> Result<()> {
+            Err((self.0)())
+        }
+    }
+
+    // Answers every request with not modified, like a misbehaving cache
+    struct AlwaysNotModified;
+
+    impl Source for AlwaysNotModified {
+        fn ready(&self) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn fetch_meta(&self, _etag: Option<&str>) -> Result<MetaFetch> {
+            Ok(MetaFetch::NotModified)
+        }
+
+        fn fetch_image(&self, _meta: &ExternalMeta, _dest: &mut (dyn Write + Send)) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn down() -> anyhow::Error {
+        anyhow!("Connection refused").context(ErrorKind::Network)
+    }
+
+    fn source(dir: &TempDir, mirrors: Vec<(&str, Box<dyn Source>)>) -> MirrorSource {
+        MirrorSource::new(
+            logger(),
+            dir.path().to_path_buf(),
+            mirrors
+                .into_iter()
+                .enumerate()
+                .map(|(i, (name, s))| (name.to_string(), i as i32, s))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn etags_are_only_sent_to_their_mirror() {
+        let dir = TempDir::new().unwrap();
+        let (b, b_sent) = Tagged::new("\"b1\"");
+        let s = source(
+            &dir,
+            vec![("a", Box::new(Failing(down))), ("b", Box::new(b))],
+        );
+        assert!(matches!(
+            s.fetch_meta(None).unwrap(),
+            MetaFetch::Fetched(..)
+        ));
+        assert_eq!(
+            read_mirror_state(dir.path()).unwrap().mirrors["b"].etag,
+            Some("\"b1\"".to_string())
+        );
+        assert!(matches!(
+            s.fetch_meta(Some("\"b1\"")).unwrap(),
+            MetaFetch::NotModified
+        ));
+        assert_eq!(
+            *b_sent.lock().unwrap(),
+            vec![None, Some("\"b1\"".to_string())]
+        );
+
+        // A mirror that didn't issue the cached etag is asked for the whole meta
+        let (a, a_sent) = Tagged::new("\"a1\"");
+        let s = source(
+            &dir,
+            vec![("a", Box::new(a)), ("b", Box::new(Failing(down)))],
+        );
+        assert!(matches!(
+            s.fetch_meta(Some("\"b1\"")).unwrap(),
+            MetaFetch::Fetched(..)
+        ));
+        assert_eq!(*a_sent.lock().unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn unexpected_not_modified_fails_the_download() {
+        let dir = TempDir::new().unwrap();
+        let (a, _) = Tagged::new("\"a1\"");
+        let s = source(
+            &dir,
+            vec![("a", Box::new(a)), ("b", Box::new(AlwaysNotModified))],
+        );
+        let meta = match s.fetch_meta(None).unwrap() {
+            MetaFetch::Fetched(meta, _) => meta,
+            MetaFetch::NotModified => panic!("Expected a meta"),
+        };
+        // The meta's mirror fails, then the other can't confirm it has the version
+        assert!(s.fetch_image(&meta, &mut vec![]).is_err());
+        let e = s.fetch_image(&meta, &mut vec![]).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Server));
+    }
+
+    #[test]
+    fn all_mirrors_failing_keeps_the_underlying_error() {
+        let dir = TempDir::new().unwrap();
+        fn rejected() -> anyhow::Error {
+            check_s3_status(403).unwrap_err()
+        }
+        fn unconfigured() -> anyhow::Error {
+            anyhow!("No credentials").context(ErrorKind::Config)
+        }
+        fn unclassified() -> anyhow::Error {
+            anyhow!("Connection refused")
+        }
+
+        // Permanent only if every mirror's error is
+        let e = source(
+            &dir,
+            vec![
+                ("a", Box::new(Failing(rejected))),
+                ("b", Box::new(Failing(rejected))),
+            ],
+        )
+        .fetch_meta(None)
+        .err()
+        .unwrap();
+        assert!(e.downcast_ref::<Permanent>().is_some());
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Credentials));
+        let e = source(
+            &dir,
+            vec![
+                ("a", Box::new(Failing(rejected))),
+                ("b", Box::new(Failing(down))),
+            ],
+        )
+        .fetch_meta(None)
+        .err()
+        .unwrap();
+        assert!(e.downcast_ref::<Permanent>().is_none());
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Network));
+        assert!(format!("{:#}", e).contains("a: "));
+
+        // A specific kind wins over network
+        let e = source(
+            &dir,
+            vec![
+                ("a", Box::new(Failing(down))),
+                ("b", Box::new(Failing(unconfigured))),
+            ],
+        )
+        .fetch_meta(None)
+        .err()
+        .unwrap();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Config));
+        let e = source(&dir, vec![("a", Box::new(Failing(unclassified)))])
+            .fetch_meta(None)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Network));
+    }
+}
@@ -0,0 +1,339 @@
+// Network readiness: default routes read from the kernel over netlink (ipv4 and ipv6) and
+// optionally a dns lookup and tcp connect to the update endpoint
+use crate::{ec, info};
+use anyhow::{anyhow, Context, Result};
+use slog::Logger;
+use std::{
+    io,
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+const NLMSG_HEADER_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+const RTN_UNICAST: u8 = 1;
+const RT_TABLE_MAIN: u32 = 254;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_TABLE: u16 = 15;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefaultRoute {
+    pub ipv6: bool,
+    pub gateway: Option<IpAddr>,
+    // Interface index
+    pub oif: Option<u32>,
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_ne_bytes([b[at], b[at + 1]])
+}
+
+fn read_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+// Parses one recv'd buffer of a RTM_GETROUTE dump, appending default routes in the main table.
+// Returns true once the end of the dump is reached.
+pub fn parse_route_dump(buf: &[u8], out: &mut Vec<DefaultRoute>) -> Result<bool> {
+    let mut at = 0;
+    while at + NLMSG_HEADER_LEN <= buf.len() {
+        let len = read_u32(buf, at) as usize;
+        if len < NLMSG_HEADER_LEN || at + len > buf.len() {
+            return Err(anyhow!("Truncated netlink message"));
+        }
+        let msg = &buf[at..at + len];
+        at += align(len);
+        match read_u16(msg, 4) {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let code = i32::from_ne_bytes(msg[16..20].try_into().unwrap());
+                return Err(anyhow!("Netlink route dump failed")
+                    .context(io::Error::from_raw_os_error(-code)));
+            }
+            RTM_NEWROUTE => {}
+            _ => continue,
+        }
+        if msg.len() < NLMSG_HEADER_LEN + RTMSG_LEN {
+            return Err(anyhow!("Truncated route message"));
+        }
+        let rtm = &msg[NLMSG_HEADER_LEN..];
+        let family = rtm[0] as i32;
+        let dst_len = rtm[1];
+        let mut table = rtm[4] as u32;
+        let kind = rtm[7];
+        let mut route = DefaultRoute {
+            ipv6: family == libc::AF_INET6,
+            gateway: None,
+            oif: None,
+        };
+        let mut attr_at = NLMSG_HEADER_LEN + align(RTMSG_LEN);
+        while attr_at + 4 <= msg.len() {
+            let attr_len = read_u16(msg, attr_at) as usize;
+            if attr_len < 4 || attr_at + attr_len > msg.len() {
+                break;
+            }
+            let data = &msg[attr_at + 4..attr_at + attr_len];
+            match (read_u16(msg, attr_at + 2), data.len()) {
+                (RTA_TABLE, 4) => table = read_u32(data, 0),
+                (RTA_OIF, 4) => route.oif = Some(read_u32(data, 0)),
+                (RTA_GATEWAY, 4) => {
+                    route.gateway = Some(IpAddr::V4(Ipv4Addr::from(
+                        <[u8; 4]>::try_from(data).unwrap(),
+                    )))
+                }
+                (RTA_GATEWAY, 16) => {
+                    route.gateway = Some(IpAddr::V6(Ipv6Addr::from(
+                        <[u8; 16]>::try_from(data).unwrap(),
+                    )))
+                }
+                _ => {}
+            }
+            attr_at += align(attr_len);
+        }
+        if (family == libc::AF_INET || family == libc::AF_INET6)
+            && dst_len == 0
+            && kind == RTN_UNICAST
+            && table == RT_TABLE_MAIN
+        {
+            out.push(route);
+        }
+    }
+    Ok(false)
+}
+
+struct NetlinkSocket(libc::c_int);
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+// Default routes for both address families, like `ip route show default` + `ip -6 ...`
+pub fn default_routes() -> Result<Vec<DefaultRoute>> {
+    ec!(("Error dumping routes over netlink"), {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to open netlink socket");
+        }
+        let sock = NetlinkSocket(fd);
+
+        let mut req = [0u8; NLMSG_HEADER_LEN + RTMSG_LEN];
+        let req_len = req.len() as u32;
+        req[0..4].copy_from_slice(&req_len.to_ne_bytes());
+        req[4..6].copy_from_slice(&RTM_GETROUTE.to_ne_bytes());
+        req[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+        req[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        req[NLMSG_HEADER_LEN] = libc::AF_UNSPEC as u8;
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        let sent = unsafe {
+            libc::sendto(
+                sock.0,
+                req.as_ptr() as *const libc::c_void,
+                req.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to send route request");
+        }
+
+        let mut out = vec![];
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let got =
+                unsafe { libc::recv(sock.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if got < 0 {
+                return Err(io::Error::last_os_error()).context("Failed to receive routes");
+            }
+            if got == 0 || parse_route_dump(&buf[..got as usize], &mut out)? {
+                break;
+            }
+        }
+        Ok(out)
+    })
+}
+
+fn interface_name(index: u32) -> String {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        return format!("#{}", index);
+    }
+    unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .to_string()
+}
+
+// Resolves `endpoint` (host:port) and connects to any of its addresses
+pub fn check_endpoint(log: &Logger, endpoint: &str, timeout: Duration) -> Result<()> {
+    ec!(("Error reaching {}", endpoint), {
+        let addrs = endpoint
+            .to_socket_addrs()
+            .context("DNS lookup failed")?
+            .collect::<Vec<_>>();
+        info!(
+            log,
+            "Resolved update endpoint",
+            endpoint = endpoint,
+            addresses = format!("{:?}", addrs)
+        );
+        let mut errors = vec![];
+        for addr in &addrs {
+            match TcpStream::connect_timeout(addr, timeout) {
+                Ok(_) => {
+                    info!(
+                        log,
+                        "Connected to update endpoint",
+                        address = addr.to_string()
+                    );
+                    return Ok(());
+                }
+                Err(e) => errors.push(format!("{}: {}", addr, e)),
+            }
+        }
+        Err(anyhow!("Couldn't connect to any address: {:?}", errors))
+    })
+}
+
+// Ready once there's a default route for either address family and, if given, the endpoint
+// can be reached
+pub fn network_ready(log: &Logger, endpoint: Option<&str>) -> Result<bool> {
+    let routes = default_routes()?;
+    for family in [false, true] {
+        let found = routes
+            .iter()
+            .filter(|r| r.ipv6 == family)
+            .collect::<Vec<_>>();
+        info!(
+            log,
+            "Default routes",
+            family = if family { "ipv6" } else { "ipv4" },
+            routes = found
+                .iter()
+                .map(|r| format!(
+                    "{} via {}",
+                    r.oif.map(interface_name).unwrap_or("?".to_string()),
+                    r.gateway
+                        .map(|g| g.to_string())
+                        .unwrap_or("link".to_string())
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if routes.is_empty() {
+        return Ok(false);
+    }
+    if let Some(endpoint) = endpoint {
+        if let Err(e) = check_endpoint(log, endpoint, Duration::from_secs(10)) {
+            info!(
+                log,
+                "Update endpoint isn't reachable yet",
+                err = format!("{:?}", e)
+            );
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::logger;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
+
+    // Netlink route message with family, dst prefix length, table and attributes
+    fn route_msg(family: u8, dst_len: u8, table: u8, attrs: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![family, dst_len, 0, 0, table, 3, 0, 1, 0, 0, 0, 0];
+        for (kind, data) in attrs {
+            body.extend(((data.len() + 4) as u16).to_ne_bytes());
+            body.extend(kind.to_ne_bytes());
+            body.extend(data);
+            body.resize((body.len() + 3) & !3, 0);
+        }
+        let mut msg = vec![];
+        msg.extend(((body.len() + 16) as u32).to_ne_bytes());
+        msg.extend(24u16.to_ne_bytes());
+        msg.extend([0; 10]);
+        msg.extend(body);
+        msg
+    }
+
+    #[test]
+    fn route_dump_finds_default_routes_for_both_families() {
+        let v6_gw: Ipv6Addr = "fe80::1".parse().unwrap();
+        let mut buf = vec![];
+        // Ipv4 default route
+        buf.extend(route_msg(
+            2,
+            0,
+            254,
+            &[(5, vec![10, 0, 0, 1]), (4, 2u32.to_ne_bytes().to_vec())],
+        ));
+        // Ipv4 subnet route
+        buf.extend(route_msg(2, 24, 254, &[(4, 2u32.to_ne_bytes().to_vec())]));
+        // Ipv6 default route, table in an attribute
+        buf.extend(route_msg(
+            10,
+            0,
+            252,
+            &[
+                (15, 254u32.to_ne_bytes().to_vec()),
+                (5, v6_gw.octets().to_vec()),
+            ],
+        ));
+        // Default route in another table
+        buf.extend(route_msg(10, 0, 100, &[]));
+        let mut routes = vec![];
+        assert!(!parse_route_dump(&buf, &mut routes).unwrap());
+        assert_eq!(
+            routes,
+            vec![
+                DefaultRoute {
+                    ipv6: false,
+                    gateway: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+                    oif: Some(2),
+                },
+                DefaultRoute {
+                    ipv6: true,
+                    gateway: Some(IpAddr::V6(v6_gw)),
+                    oif: None,
+                },
+            ]
+        );
+
+        let mut done = 20u32.to_ne_bytes().to_vec();
+        done.extend(3u16.to_ne_bytes());
+        done.extend([0; 14]);
+        assert!(parse_route_dump(&done, &mut routes).unwrap());
+    }
+
+    #[test]
+    fn endpoint_check_connects_to_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("localhost:{}", listener.local_addr().unwrap().port());
+        check_endpoint(&logger(), &endpoint, std::time::Duration::from_secs(1)).unwrap();
+        drop(listener);
+        assert!(check_endpoint(&logger(), &endpoint, std::time::Duration::from_secs(1)).is_err());
+    }
+}
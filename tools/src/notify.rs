@@ -0,0 +1,185 @@
+// systemd's sd_notify protocol, so the unit shows what the updater is doing and systemd can
+// restart it if a copy stops making progress. Does nothing unless started by systemd with
+// `NotifyAccess` set.
+use crate::{info, warn};
+use slog::Logger;
+use std::{
+    env,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+struct NotifyState {
+    status: String,
+    last_ping: Option<Instant>,
+}
+
+pub struct Notifier {
+    log: Logger,
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    // Pings are expected at least this often
+    watchdog: Option<Duration>,
+    state: Mutex<NotifyState>,
+    ready: AtomicBool,
+    // Copies in progress, during which only progress pings the watchdog
+    copying: AtomicUsize,
+}
+
+// Lets the keepalive thread ping the watchdog again when dropped
+pub struct Copying(Arc<Notifier>);
+
+impl Drop for Copying {
+    fn drop(&mut self) {
+        self.0.copying.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn notify_socket(path: &str) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    Ok((UnixDatagram::unbound()?, addr))
+}
+
+impl Notifier {
+    fn new(
+        log: Logger,
+        socket: Option<(UnixDatagram, SocketAddr)>,
+        watchdog: Option<Duration>,
+    ) -> Notifier {
+        Notifier {
+            log: log,
+            socket: socket,
+            watchdog: watchdog,
+            state: Mutex::new(NotifyState {
+                status: String::new(),
+                last_ping: None,
+            }),
+            ready: AtomicBool::new(false),
+            copying: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn disabled(log: Logger) -> Arc<Notifier> {
+        Arc::new(Notifier::new(log, None, None))
+    }
+
+    // Uses the socket and watchdog interval systemd passed in the environment
+    pub fn from_env(log: Logger) -> Arc<Notifier> {
+        let path = match env::var("NOTIFY_SOCKET") {
+            Ok(p) if !p.is_empty() => p,
+            _ => return Notifier::disabled(log),
+        };
+        // The watchdog is meant for another process if the pid doesn't match
+        let for_us = env::var("WATCHDOG_PID")
+            .map(|p| p == std::process::id().to_string())
+            .unwrap_or(true);
+        let watchdog = match env::var("WATCHDOG_USEC").map(|u| u.parse::<u64>()) {
+            Ok(Ok(usec)) if usec > 0 && for_us => Some(Duration::from_micros(usec)),
+            _ => None,
+        };
+        match Notifier::open(log.clone(), &path, watchdog) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!(
+                    log,
+                    "Failed to open systemd notify socket",
+                    path = &path,
+                    err = format!("{:?}", e)
+                );
+                Notifier::disabled(log)
+            }
+        }
+    }
+
+    // Notifies the socket at `path` (`@` for the abstract namespace). With a watchdog interval,
+    // outside copies the watchdog is pinged from a background thread.
+    pub fn open(
+        log: Logger,
+        path: &str,
+        watchdog: Option<Duration>,
+    ) -> std::io::Result<Arc<Notifier>> {
+        let notifier = Arc::new(Notifier::new(log, Some(notify_socket(path)?), watchdog));
+        if let Some(interval) = watchdog {
+            info!(
+                notifier.log,
+                "Pinging systemd watchdog",
+                interval_ms = interval.as_millis() as u64
+            );
+            let notifier = notifier.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval / 4);
+                if notifier.copying.load(Ordering::SeqCst) == 0 {
+                    notifier.ping();
+                }
+            });
+        }
+        Ok(notifier)
+    }
+
+    fn send(&self, message: &str) {
+        if let Some((socket, addr)) = &self.socket {
+            if let Err(e) = socket.send_to_addr(message.as_bytes(), addr) {
+                warn!(
+                    self.log,
+                    "Failed to notify systemd",
+                    message = message,
+                    err = format!("{:?}", e)
+                );
+            }
+        }
+    }
+
+    // Startup is done, units ordered after this one can start. Only sent once.
+    pub fn ready(&self) {
+        if !self.ready.swap(true, Ordering::SeqCst) {
+            self.send("READY=1");
+        }
+    }
+
+    // Shown by `systemctl status`, only sent when it changes
+    pub fn status(&self, status: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.status == status {
+            return;
+        }
+        state.status = status.to_string();
+        self.send(&format!("STATUS={}", status));
+    }
+
+    // Tells the watchdog the updater is alive, at most a few times per interval
+    pub fn ping(&self) {
+        let interval = match self.watchdog {
+            Some(i) => i,
+            None => return,
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .last_ping
+                .map(|t| t.elapsed() < interval / 8)
+                .unwrap_or(false)
+            {
+                return;
+            }
+            state.last_ping = Some(Instant::now());
+        }
+        self.send("WATCHDOG=1");
+    }
+
+    // Until the guard is dropped the watchdog is only pinged by progress, so a stalled copy
+    // gets the updater killed
+    pub fn copying(self: &Arc<Self>) -> Copying {
+        self.copying.fetch_add(1, Ordering::SeqCst);
+        Copying(self.clone())
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    io,
+    process::{Command, Output},
+    sync::Arc,
+};
+#[cfg(any(test, feature = "testing"))]
+use std::{os::unix::process::ExitStatusExt, path::PathBuf, process::ExitStatus, sync::Mutex};
+
+// Everything the tools spawn goes through this so it can be faked in tests
+pub trait Runner: Send + Sync {
+    fn output(&self, c: &mut Command) -> io::Result<Output>;
+}
+
+pub struct SystemRunner;
+
+impl Runner for SystemRunner {
+    fn output(&self, c: &mut Command) -> io::Result<Output> {
+        c.output()
+    }
+}
+
+// Program followed by args
+pub fn command_line(c: &Command) -> Vec<String> {
+    let mut out = vec![c.get_program().to_string_lossy().to_string()];
+    out.extend(c.get_args().map(|a| a.to_string_lossy().to_string()));
+    out
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub fn fake_output(code: i32, stdout: &[u8]) -> Output {
+    Output {
+        status: ExitStatus::from_raw(code << 8),
+        stdout: stdout.to_vec(),
+        stderr: vec![],
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+type FakeHandler = Box<dyn Fn(&[String]) -> Option<Output> + Send + Sync>;
+
+// Records every command instead of running it. The handler can script a result for a command
+// line, otherwise the command succeeds with no output.
+#[cfg(any(test, feature = "testing"))]
+pub struct FakeRunner {
+    calls: Mutex<Vec<Vec<String>>>,
+    handler: FakeHandler,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl FakeRunner {
+    pub fn new<F: Fn(&[String]) -> Option<Output> + Send + Sync + 'static>(
+        handler: F,
+    ) -> FakeRunner {
+        FakeRunner {
+            calls: Mutex::new(vec![]),
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    // Calls whose program is `program`
+    pub fn calls_to(&self, program: &str) -> Vec<Vec<String>> {
+        self.calls()
+            .into_iter()
+            .filter(|c| PathBuf::from(&c[0]).ends_with(program))
+            .collect()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Runner for FakeRunner {
+    fn output(&self, c: &mut Command) -> io::Result<Output> {
+        let line = command_line(c);
+        self.calls.lock().unwrap().push(line.clone());
+        Ok((self.handler)(&line).unwrap_or_else(|| fake_output(0, b"")))
+    }
+}
+
+impl<T: Runner + ?Sized> Runner for Arc<T> {
+    fn output(&self, c: &mut Command) -> io::Result<Output> {
+        (**self).output(c)
+    }
+}
@@ -0,0 +1,343 @@
+// Device specific settings in `config.json` on the rw partition, merged over the version meta so
+// they can change without shipping a new version. Only the fields here can be overridden.
+use crate::{credentials::CredentialSource, ec, info, read_bytes, ErrorKind, InternalMeta};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+pub const RUNTIME_CONFIG_FILE: &'static str = "config.json";
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Window {
+    // Local time of day, "HH:MM". A window ending before it starts runs past midnight.
+    pub start: String,
+    pub end: String,
+}
+
+fn parse_time(t: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(t, "%H:%M").context(format!("Invalid time {:?}, expected HH:MM", t))
+}
+
+impl Window {
+    pub fn validate(&self) -> Result<()> {
+        parse_time(&self.start)?;
+        parse_time(&self.end)?;
+        Ok(())
+    }
+
+    // `now` if it's within the window, otherwise when the window next opens
+    pub fn next_open<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> DateTime<Tz> {
+        let (start, end) = match (parse_time(&self.start), parse_time(&self.end)) {
+            (Ok(s), Ok(e)) => (s, e),
+            _ => return now.clone(),
+        };
+        let local = now.naive_local();
+        let t = local.time();
+        let open = match start <= end {
+            true => t >= start && t < end,
+            false => t >= start || t < end,
+        };
+        if open || start == end {
+            return now.clone();
+        }
+        let mut day = local.date();
+        if t >= start {
+            day += Duration::days(1);
+        }
+        now.timezone()
+            .from_local_datetime(&day.and_time(start))
+            .earliest()
+            // Start skipped by a DST change
+            .unwrap_or_else(|| now.clone() + Duration::hours(1))
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    // Seconds between checks while the updater keeps running (`update --serve`)
+    #[serde(default)]
+    pub interval: Option<u64>,
+    // Only reboot into new versions found by a check within this daily window. Outside it new
+    // versions are left staged, or with a control socket the reboot waits for the window.
+    #[serde(default)]
+    pub window: Option<Window>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    // One of the version's `channels`, replacing its object path
+    #[serde(default)]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    // Don't install or activate new versions
+    #[serde(default)]
+    pub hold: Option<bool>,
+    // Replaces the version's http proxy, "" for none
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub credentials: Option<CredentialSource>,
+}
+
+pub fn runtime_config_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(RUNTIME_CONFIG_FILE)
+}
+
+pub fn parse_runtime_config(data: &[u8]) -> Result<RuntimeConfig> {
+    serde_json::from_slice(data).context("Failed to parse runtime config")
+}
+
+pub fn read_runtime_config(state_dir: &Path) -> Result<Option<RuntimeConfig>> {
+    let path = runtime_config_path(state_dir);
+    if !path.exists() {
+        return Ok(None);
+    }
+    ec!(
+        ("Error reading runtime config {}", path.to_string_lossy()),
+        { Ok(Some(parse_runtime_config(&read_bytes(&path)?)?)) }
+    )
+    .context(ErrorKind::Config)
+}
+
+impl RuntimeConfig {
+    // Checks everything that can't be expressed by the types, against the version it applies to
+    pub fn validate(&self, meta: &InternalMeta) -> Result<()> {
+        let mut problems = vec![];
+        if let Some(channel) = &self.channel {
+            if !meta.channels.contains_key(channel) {
+                let mut known = meta.channels.keys().collect::<Vec<_>>();
+                known.sort();
+                problems.push(format!(
+                    "channel {:?} isn't one of the version's channels {:?}",
+                    channel, known
+                ));
+            }
+        }
+        if let Some(schedule) = &self.schedule {
+            if schedule.interval == Some(0) {
+                problems.push("schedule.interval must be more than 0".to_string());
+            }
+            if let Some(window) = &schedule.window {
+                if let Err(e) = window.validate() {
+                    problems.push(format!("schedule.window: {:#}", e));
+                }
+            }
+        }
+        if let Some(proxy) = self.proxy.as_ref().filter(|p| !p.is_empty()) {
+            match Url::parse(proxy) {
+                Ok(u) if u.scheme() == "http" && u.host_str().is_some() => {}
+                Ok(_) => problems.push("proxy must be an http:// url with a host".to_string()),
+                Err(e) => problems.push(format!("proxy: {}", e)),
+            }
+        }
+        if let Some(CredentialSource::File { path }) = &self.credentials {
+            if !path.is_absolute() {
+                problems.push("credentials.path must be absolute".to_string());
+            }
+        }
+        if !problems.is_empty() {
+            return Err(anyhow!("Invalid runtime config: {}", problems.join("; "))
+                .context(ErrorKind::Config));
+        }
+        Ok(())
+    }
+
+    // Names of the fields that are set, for logging
+    pub fn fields(&self) -> Vec<&'static str> {
+        let mut out = vec![];
+        if self.channel.is_some() {
+            out.push("channel");
+        }
+        if self.schedule.is_some() {
+            out.push("schedule");
+        }
+        if self.hold.is_some() {
+            out.push("hold");
+        }
+        if self.proxy.is_some() {
+            out.push("proxy");
+        }
+        if self.credentials.is_some() {
+            out.push("credentials");
+        }
+        out
+    }
+
+    pub fn apply(&self, meta: &InternalMeta) -> InternalMeta {
+        let mut out = meta.clone();
+        if let Some(c) = &self.channel {
+            out.channel = Some(c.clone());
+        }
+        if let Some(s) = &self.schedule {
+            out.schedule = s.clone();
+        }
+        if let Some(h) = self.hold {
+            out.hold = h;
+        }
+        if let Some(p) = &self.proxy {
+            out.http.proxy = Some(p.clone()).filter(|p| !p.is_empty());
+        }
+        if let Some(c) = &self.credentials {
+            out.credentials = Some(c.clone());
+        }
+        out
+    }
+}
+
+// The version meta with a runtime config applied and the channel resolved
+pub fn apply_runtime_config(
+    log: &Logger,
+    meta: InternalMeta,
+    config: Option<RuntimeConfig>,
+) -> Result<InternalMeta> {
+    let mut meta = match config {
+        Some(config) => {
+            config.validate(&meta)?;
+            info!(
+                log,
+                "Applying runtime config",
+                fields = config.fields().join(",")
+            );
+            config.apply(&meta)
+        }
+        None => meta,
+    };
+    if let Some(channel) = &meta.channel {
+        meta.object_path = meta
+            .channels
+            .get(channel)
+            .ok_or_else(|| anyhow!("Unknown channel {:?}", channel).context(ErrorKind::Config))?
+            .clone();
+    }
+    Ok(meta)
+}
+
+// The version meta with the runtime config in `state_dir`, if any, applied
+pub fn effective_meta(log: &Logger, meta: InternalMeta, state_dir: &Path) -> Result<InternalMeta> {
+    apply_runtime_config(log, meta, read_runtime_config(state_dir)?)
+}
+
+// For printing, with secrets replaced
+pub fn redacted(meta: &InternalMeta) -> serde_json::Value {
+    fn walk(v: &mut serde_json::Value) {
+        match v {
+            serde_json::Value::Object(o) => {
+                for (k, v) in o.iter_mut() {
+                    match k.as_str() {
+                        "secret_key" | "session_token" if !v.is_null() => {
+                            *v = serde_json::Value::String("<redacted>".to_string())
+                        }
+                        "proxy" => {
+                            if let Some(mut u) = v.as_str().and_then(|p| Url::parse(p).ok()) {
+                                if u.password().is_some() {
+                                    let _ = u.set_password(Some("redacted"));
+                                    *v = serde_json::Value::String(u.to_string());
+                                }
+                            }
+                        }
+                        _ => walk(v),
+                    }
+                }
+            }
+            serde_json::Value::Array(a) => a.iter_mut().for_each(walk),
+            _ => {}
+        }
+    }
+    let mut out = serde_json::to_value(meta).unwrap();
+    walk(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{logger, meta};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn runtime_config_overrides_whitelisted_fields() {
+        let dir = TempDir::new().unwrap();
+        let mut current = meta("current");
+        current
+            .channels
+            .insert("beta".to_string(), "system-beta".to_string());
+        let write = |config: serde_json::Value| {
+            fs::write(
+                dir.path().join("config.json"),
+                serde_json::to_vec(&config).unwrap(),
+            )
+            .unwrap()
+        };
+
+        let effective = effective_meta(&logger(), current.clone(), dir.path()).unwrap();
+        assert_eq!(effective.object_path, "system");
+
+        write(serde_json::json!({
+            "channel": "beta",
+            "hold": true,
+            "proxy": "http://proxy:3128",
+            "schedule": {"interval": 600, "window": {"start": "02:00", "end": "04:00"}},
+            "credentials": {"type": "file", "path": "/rw/keys.json"},
+        }));
+        let effective = effective_meta(&logger(), current.clone(), dir.path()).unwrap();
+        assert_eq!(effective.object_path, "system-beta");
+        assert!(effective.hold);
+        assert_eq!(effective.http.proxy.as_deref(), Some("http://proxy:3128"));
+        assert_eq!(effective.schedule.interval, Some(600));
+        assert!(matches!(
+            effective.credentials,
+            Some(CredentialSource::File { .. })
+        ));
+        assert_eq!(effective.uuid, "current");
+
+        // Only whitelisted fields, checked against the version
+        write(serde_json::json!({"uuid": "other"}));
+        let err = effective_meta(&logger(), current.clone(), dir.path())
+            .err()
+            .unwrap();
+        assert_eq!(crate::exit_code(&err), 2);
+        write(serde_json::json!({
+            "channel": "alpha",
+            "proxy": "socks5://proxy",
+            "schedule": {"window": {"start": "2am", "end": "04:00"}},
+        }));
+        let err = format!(
+            "{:#}",
+            effective_meta(&logger(), current, dir.path())
+                .err()
+                .unwrap()
+        );
+        assert!(err.contains("channel \"alpha\""), "{}", err);
+        assert!(err.contains("proxy must be"), "{}", err);
+        assert!(err.contains("Invalid time \"2am\""), "{}", err);
+    }
+
+    #[test]
+    fn maintenance_window_next_open() {
+        let at = |t: &str| {
+            chrono::DateTime::parse_from_rfc3339(&format!("2024-03-{}:00Z", t))
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        let window = |start: &str, end: &str| Window {
+            start: start.to_string(),
+            end: end.to_string(),
+        };
+        let night = window("22:00", "02:00");
+        assert_eq!(night.next_open(&at("01T23:00")), at("01T23:00"));
+        assert_eq!(night.next_open(&at("01T01:59")), at("01T01:59"));
+        assert_eq!(night.next_open(&at("01T02:00")), at("01T22:00"));
+        let early = window("02:00", "04:00");
+        assert_eq!(early.next_open(&at("01T01:00")), at("01T02:00"));
+        assert_eq!(early.next_open(&at("01T03:00")), at("01T03:00"));
+        assert_eq!(early.next_open(&at("01T05:00")), at("02T02:00"));
+    }
+}
@@ -0,0 +1,479 @@
+// Backends for the updater that act on the running system
+use crate::{
+    check_s3_status,
+    clock::{
+        clock_state, time_floor, use_server_date, use_system_clock, ClockFix, ClockState,
+        TIMESYNC_FLAG_PATH,
+    },
+    credentials::{CredentialProvider, CredentialSource},
+    ec, find_root_parts, grub_env_list, grub_env_set, grub_env_unset,
+    http::HttpClient,
+    info,
+    mirrors::MirrorSource,
+    mount_boot,
+    network::network_ready,
+    presign_url, read_slots,
+    runner::Runner,
+    updater::{BootConfig, BootEntry, Bootloader, Disks, MetaFetch, Reboot, Source},
+    version_endpoint, warn, write_slots, ErrorKind, ExternalMeta, Host, InternalMeta, LsblkDevice,
+    SimpleCommand, SlotState,
+};
+use anyhow::{anyhow, Context, Result};
+use askama::Template;
+use chrono::Utc;
+use slog::Logger;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+pub struct S3Source {
+    log: Logger,
+    // Bucket is created per request since credentials may expire
+    current: InternalMeta,
+    credentials: CredentialProvider,
+    http: HttpClient,
+    // Checked for reachability before the network is considered ready
+    endpoint: Option<String>,
+    meta_path: String,
+}
+
+// Requests are made with presigned urls so they go through our own http client
+const PRESIGN_EXPIRY_SECS: u32 = 3600;
+
+impl S3Source {
+    pub fn new(log: Logger, current: &InternalMeta) -> Result<S3Source> {
+        let http = HttpClient::new(&current.http).context(ErrorKind::Config)?;
+        // Behind a proxy only the proxy is reachable directly
+        let endpoint = match (current.check_endpoint, http.proxy_endpoint()) {
+            (false, _) => None,
+            (true, Some(proxy)) => Some(proxy),
+            (true, None) => Some(version_endpoint(current).context(ErrorKind::Config)?),
+        };
+        Ok(S3Source {
+            log: log,
+            current: current.clone(),
+            credentials: CredentialProvider::new(CredentialSource::of(current), &http),
+            http: http,
+            endpoint: endpoint,
+            meta_path: format!("{}.meta", current.object_path),
+        })
+    }
+
+    fn presign(&self, path: &str) -> Result<String> {
+        let credentials = self.credentials.get().context(ErrorKind::Credentials)?;
+        presign_url(&self.current, credentials, "GET", path, PRESIGN_EXPIRY_SECS)
+    }
+}
+
+impl Source for S3Source {
+    fn ready(&self) -> Result<bool> {
+        if !network_ready(&self.log, self.endpoint.as_deref())? {
+            return Ok(false);
+        }
+        let config = &self.current.clock;
+        let floor = time_floor(&self.current);
+        match clock_state(config, floor, Utc::now(), Path::new(TIMESYNC_FLAG_PATH)) {
+            ClockState::Ok => {
+                use_system_clock();
+                Ok(true)
+            }
+            ClockState::Implausible => {
+                warn!(
+                    self.log,
+                    "System clock is earlier than the version build time, requests would fail signature checks",
+                    now = Utc::now().to_rfc3339(),
+                    floor = floor.to_rfc3339()
+                );
+                match config.fix {
+                    ClockFix::Wait => {
+                        info!(self.log, "Waiting for the clock to be set");
+                        Ok(false)
+                    }
+                    ClockFix::ServerDate => {
+                        let server = self
+                            .http
+                            .server_date(&self.presign(&self.meta_path)?)
+                            .context(ErrorKind::Network)?;
+                        if server < floor {
+                            return Err(anyhow!(
+                                "Server date {} is also earlier than the version build time",
+                                server
+                            ));
+                        }
+                        use_server_date(&self.log, server, floor);
+                        Ok(true)
+                    }
+                }
+            }
+            ClockState::Unsynced => {
+                info!(
+                    self.log,
+                    "Waiting for systemd-timesyncd to synchronize the clock"
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+        let (status, etag, body) = self
+            .http
+            .get_conditional(&self.presign(&self.meta_path)?, etag)
+            .context("Failed to download meta for new version")
+            .context(ErrorKind::Network)?;
+        if status == 304 {
+            return Ok(MetaFetch::NotModified);
+        }
+        check_s3_status(status)?;
+        Ok(MetaFetch::Fetched(
+            Box::new(
+                serde_json::from_slice(&body)
+                    .context("Failed to parse meta")
+                    .context(ErrorKind::Server)?,
+            ),
+            etag,
+        ))
+    }
+
+    fn fetch_image(&self, meta: &ExternalMeta, dest: &mut (dyn Write + Send)) -> Result<()> {
+        let url = self.presign(&meta.internal.object_path)?;
+        check_s3_status(download(&self.http, &url, dest)?)
+    }
+}
+
+// Remembers whether a write failed, to tell disk errors from network errors
+struct WatchedWrite<'a> {
+    inner: &'a mut (dyn Write + Send),
+    failed: bool,
+}
+
+impl Write for WatchedWrite<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let r = self.inner.write(buf);
+        self.failed |= r.is_err();
+        r
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let r = self.inner.flush();
+        self.failed |= r.is_err();
+        r
+    }
+}
+
+// Writes the body to `dest` if the response is 2xx. Returns the status.
+fn download(http: &HttpClient, url: &str, dest: &mut (dyn Write + Send)) -> Result<u16> {
+    let mut dest = WatchedWrite {
+        inner: dest,
+        failed: false,
+    };
+    match http.get(url, &mut dest) {
+        Ok(status) => Ok(status),
+        Err(e) if dest.failed => Err(e.context("Error writing image").context(ErrorKind::Disk)),
+        Err(e) => Err(e
+            .context("Error downloading image")
+            .context(ErrorKind::Network)),
+    }
+}
+
+// The version's own bucket, or every bucket it's mirrored to with failover
+pub fn version_source(
+    log: Logger,
+    current: &InternalMeta,
+    state_dir: PathBuf,
+) -> Result<Box<dyn Source>> {
+    let source = S3Source::new(log.clone(), current)?;
+    if current.mirrors.is_empty() {
+        return Ok(Box::new(source));
+    }
+    let mut mirrors: Vec<(String, i32, Box<dyn Source>)> = vec![(
+        format!("{}/{}", current.region, current.bucket),
+        0,
+        Box::new(source),
+    )];
+    for mirror in &current.mirrors {
+        mirrors.push((
+            mirror.name(),
+            mirror.priority,
+            Box::new(S3Source::new(log.clone(), &mirror.apply(current))?),
+        ));
+    }
+    Ok(Box::new(MirrorSource::new(log, state_dir, mirrors)))
+}
+
+#[derive(Template)]
+#[template(path = "grub_slots.conf", escape = "none")]
+struct GrubTemplate<'a> {
+    new: &'a InternalMeta,
+    current: &'a InternalMeta,
+    // Descending, one per remaining boot attempt
+    attempts: Vec<u32>,
+    entries: &'a [BootEntry<'a>],
+    // Entries to try in order if the default can't be loaded
+    fallback: &'a [BootEntry<'a>],
+}
+
+// Boots the new version while `organixm_attempts` counts down from `boot_attempts` (at least
+// one), then the saved entry
+fn grub_config(config: &BootConfig) -> String {
+    GrubTemplate {
+        current: config.current,
+        new: config.new,
+        attempts: (1..=config.new.boot_attempts.max(1)).rev().collect(),
+        entries: &config.entries,
+        fallback: &config.entries[1..],
+    }
+    .render()
+    .unwrap()
+}
+
+// Grub on the boot partition, mounted for each operation
+pub struct Grub {
+    log: Logger,
+    host: Host,
+}
+
+impl Grub {
+    pub fn new(log: Logger, host: Host) -> Grub {
+        Grub {
+            log: log,
+            host: host,
+        }
+    }
+}
+
+impl Bootloader for Grub {
+    fn env(&self) -> Result<HashMap<String, String>> {
+        let _mount = mount_boot(self.log.clone(), &self.host)?;
+        grub_env_list(&self.host)
+    }
+
+    fn set_env(&self, vars: &[(&str, &str)]) -> Result<()> {
+        let _mount = mount_boot(self.log.clone(), &self.host)?;
+        grub_env_set(&self.host, vars)
+    }
+
+    fn unset_env(&self, vars: &[&str]) -> Result<()> {
+        let _mount = mount_boot(self.log.clone(), &self.host)?;
+        grub_env_unset(&self.host, vars)
+    }
+
+    fn read_slots(&self) -> Result<Vec<SlotState>> {
+        let _mount = mount_boot(self.log.clone(), &self.host)?;
+        read_slots(&self.host)
+    }
+
+    fn write_slots(&self, slots: &[SlotState]) -> Result<()> {
+        let _mount = mount_boot(self.log.clone(), &self.host)?;
+        write_slots(&self.host, slots)
+    }
+
+    fn install(&self, disk: &LsblkDevice, config: &BootConfig) -> Result<()> {
+        let grub_cfg_path = self.host.boot_dir.join("grub/grub.cfg");
+        ec!(
+            (
+                "Error writing grub config {}",
+                grub_cfg_path.to_string_lossy()
+            ),
+            {
+                let _mount = mount_boot(self.log.clone(), &self.host)?;
+                File::create(&grub_cfg_path)
+                    .context("Failed to open grub config for writing")?
+                    .write_all(grub_config(config).as_bytes())
+                    .context("Failed to write grub file contents")?;
+                Command::new("grub-install")
+                    .arg("--target=i386-pc")
+                    .arg(format!(
+                        "--boot-directory={}",
+                        self.host.boot_dir.to_string_lossy()
+                    ))
+                    .arg(&disk.path)
+                    .run(&*self.host.runner)?;
+                Ok(())
+            }
+        )
+    }
+}
+
+pub struct SystemDisks {
+    log: Logger,
+    host: Host,
+}
+
+impl SystemDisks {
+    pub fn new(log: Logger, host: Host) -> SystemDisks {
+        SystemDisks {
+            log: log,
+            host: host,
+        }
+    }
+}
+
+impl Disks for SystemDisks {
+    fn root_parts(&self) -> Result<(LsblkDevice, Vec<LsblkDevice>)> {
+        find_root_parts(&self.log, &self.host)
+    }
+
+    fn open_read(&self, part: &LsblkDevice) -> Result<Box<dyn Read>> {
+        Ok(Box::new(File::open(&part.path).with_context(|| {
+            format!("Failed to open {} for reading", part.path)
+        })?))
+    }
+
+    fn open_write(&self, part: &LsblkDevice) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .write(true)
+                .open(&part.path)
+                .with_context(|| format!("Failed to open {} for writing", part.path))?,
+        ))
+    }
+}
+
+pub struct SystemReboot {
+    runner: Arc<dyn Runner>,
+}
+
+impl SystemReboot {
+    pub fn new(runner: Arc<dyn Runner>) -> SystemReboot {
+        SystemReboot { runner: runner }
+    }
+}
+
+impl Reboot for SystemReboot {
+    fn reboot(&self) -> Result<()> {
+        Command::new("reboot").run(&*self.runner)
+    }
+}
+
+// For disk images there's nothing to reboot
+pub struct NoReboot;
+
+impl Reboot for NoReboot {
+    fn reboot(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::HttpConfig, tests::meta};
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread,
+    };
+
+    fn render(boot_attempts: u32) -> String {
+        let current = meta("current");
+        let mut new = meta("new");
+        new.boot_attempts = boot_attempts;
+        grub_config(&BootConfig {
+            new: &new,
+            current: &current,
+            entries: vec![
+                BootEntry {
+                    label: "new",
+                    meta: &new,
+                },
+                BootEntry {
+                    label: "current",
+                    meta: &current,
+                },
+            ],
+        })
+    }
+
+    // The remaining attempts each branch matches and what it leaves for the next boot
+    fn countdown(cfg: &str) -> Vec<(String, String)> {
+        let lines = cfg.lines().map(|l| l.trim()).collect::<Vec<_>>();
+        lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, l)| {
+                let matched = l
+                    .strip_prefix("if [ \"${organixm_attempts}\" = \"")
+                    .or_else(|| l.strip_prefix("elif [ \"${organixm_attempts}\" = \""))?;
+                let next = lines[i + 1].strip_prefix("set organixm_attempts=")?;
+                Some((
+                    matched.trim_end_matches("\" ]; then").to_string(),
+                    next.to_string(),
+                ))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grub_config_counts_down_boot_attempts() {
+        let pairs = |p: &[(&str, &str)]| {
+            p.iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect::<Vec<_>>()
+        };
+        // No attempts would never boot the new version, it gets one
+        assert_eq!(countdown(&render(0)), pairs(&[("1", "0")]));
+        assert_eq!(countdown(&render(1)), pairs(&[("1", "0")]));
+        assert_eq!(
+            countdown(&render(3)),
+            pairs(&[("3", "2"), ("2", "1"), ("1", "0")])
+        );
+        let cfg = render(3);
+        assert_eq!(cfg.matches("set default=new").count(), 3);
+        assert_eq!(cfg.matches("save_env organixm_attempts").count(), 3);
+        assert!(cfg.contains("set default=current\n"));
+        assert!(cfg.contains("set fallback=\"current\"\n"));
+    }
+
+    // Answers a connection with each of `responses` in turn
+    fn serve(responses: &'static [&'static str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/system", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut r = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    r.read_line(&mut head).unwrap();
+                }
+                r.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("No space left on device"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn download_errors_are_network_or_disk() {
+        let http = HttpClient::new(&HttpConfig::default()).unwrap();
+        let url = serve(&[
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nimage",
+            // Cut short
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nima",
+        ]);
+        let e = download(&http, &url, &mut FullDisk).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Disk));
+        let e = download(&http, &url, &mut vec![]).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Network));
+
+        let e = download(&http, "http://127.0.0.1:1/system", &mut vec![]).unwrap_err();
+        assert_eq!(ErrorKind::of(&e), Some(ErrorKind::Network));
+    }
+}
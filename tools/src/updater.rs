@@ -0,0 +1,1073 @@
+use crate::{
+    clear_staged,
+    control::{Control, Phase, Status, Wake},
+    directives::{Directives, Requested},
+    ec, err, info,
+    lease::{write_held_lease, HeldLease, Leases},
+    notify::Notifier,
+    read_digest, read_failed_versions, read_meta_cache, read_staged, record_failed_version, retry,
+    warn, write_meta_cache, write_staged, ErrorKind, ExternalMeta, InternalMeta, LsblkDevice,
+    MetaCache, Permanent, RetryPolicy, SlotState, StagedVersion, GRUB_ATTEMPTS_VAR, GRUB_NEW_VAR,
+    GRUB_SAVED_VAR,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Local, Utc};
+use slog::Logger;
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use zstd::stream::{raw::Decoder, zio::Writer};
+
+pub enum MetaFetch {
+    // The published meta and its etag, if the source has them
+    Fetched(Box<ExternalMeta>, Option<String>),
+    // The meta hasn't changed since the passed etag
+    NotModified,
+}
+
+// Where new versions are published
+pub trait Source {
+    // Whether the source is likely reachable, polled until true
+    fn ready(&self) -> Result<bool>;
+    // Only returns the meta if it doesn't match `etag`
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch>;
+    // Write the image object as stored (compressed) to `dest`
+    fn fetch_image(&self, meta: &ExternalMeta, dest: &mut (dyn Write + Send)) -> Result<()>;
+}
+
+pub struct BootEntry<'a> {
+    pub label: &'a str,
+    pub meta: &'a InternalMeta,
+}
+
+pub struct BootConfig<'a> {
+    pub new: &'a InternalMeta,
+    pub current: &'a InternalMeta,
+    // New first, then current, then other slots newest first
+    pub entries: Vec<BootEntry<'a>>,
+}
+
+pub trait Bootloader {
+    fn env(&self) -> Result<HashMap<String, String>>;
+    fn set_env(&self, vars: &[(&str, &str)]) -> Result<()>;
+    fn unset_env(&self, vars: &[&str]) -> Result<()>;
+    fn read_slots(&self) -> Result<Vec<SlotState>>;
+    fn write_slots(&self, slots: &[SlotState]) -> Result<()>;
+    // Write the boot config and install the bootloader to `disk`
+    fn install(&self, disk: &LsblkDevice, config: &BootConfig) -> Result<()>;
+}
+
+pub trait Disks {
+    // The disk holding the root slots and its root slot partitions, ordered by label
+    fn root_parts(&self) -> Result<(LsblkDevice, Vec<LsblkDevice>)>;
+    fn open_read(&self, part: &LsblkDevice) -> Result<Box<dyn Read>>;
+    fn open_write(&self, part: &LsblkDevice) -> Result<Box<dyn Write + Send>>;
+}
+
+pub trait Reboot {
+    fn reboot(&self) -> Result<()>;
+}
+
+// A new version written to a slot, not yet activated
+pub struct Staged {
+    pub meta: ExternalMeta,
+    pub disk: LsblkDevice,
+    pub part: LsblkDevice,
+    pub label: String,
+    current_label: String,
+    known_good: Option<String>,
+    slots: Vec<SlotState>,
+}
+
+struct Layout {
+    disk: LsblkDevice,
+    parts: Vec<LsblkDevice>,
+    // The booted slot
+    current_label: String,
+    known_good: Option<String>,
+    slots: Vec<SlotState>,
+}
+
+pub struct Updater {
+    pub log: Logger,
+    pub current: InternalMeta,
+    // Persistent state (like failed versions)
+    pub state_dir: PathBuf,
+    pub source: Box<dyn Source>,
+    pub bootloader: Box<dyn Bootloader>,
+    pub disks: Box<dyn Disks>,
+    pub reboot: Box<dyn Reboot>,
+    pub network_policy: RetryPolicy,
+    pub meta_policy: RetryPolicy,
+    pub download_policy: RetryPolicy,
+    // Pause between activating and rebooting so the logs get out
+    pub reboot_delay: Duration,
+    // Reboot coordination with other devices, if configured
+    pub leases: Option<Leases>,
+    // Pause between attempts to get a reboot lease
+    pub lease_interval: Duration,
+    // Local control socket clients, told about progress and asked before rebooting
+    pub control: Option<Arc<Control>>,
+    // systemd, told about progress and pinged while copies make progress
+    pub notifier: Arc<Notifier>,
+    // Remote directives to this device, if configured
+    pub directives: Option<Directives>,
+    status: Arc<Mutex<Status>>,
+}
+
+// Where status changes go
+#[derive(Clone)]
+struct Reporter {
+    status: Arc<Mutex<Status>>,
+    control: Option<Arc<Control>>,
+    notifier: Arc<Notifier>,
+}
+
+impl Reporter {
+    fn report<F: Fn(&mut Status)>(&self, f: F) {
+        let text = {
+            let mut status = self.status.lock().unwrap();
+            f(&mut status);
+            status.describe()
+        };
+        if let Some(control) = &self.control {
+            control.update(&f);
+        }
+        self.notifier.status(&text);
+    }
+}
+
+// Reports bytes written to the slot
+struct ProgressWriter {
+    inner: Box<dyn Write + Send>,
+    reporter: Reporter,
+    size: u64,
+    written: u64,
+    reported: u64,
+}
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        self.reporter.notifier.ping();
+        // Every percent
+        if self.written - self.reported >= (self.size / 100).max(1) || self.written >= self.size {
+            self.reported = self.written;
+            let written = self.written;
+            self.reporter.report(|s| s.downloaded = Some(written));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Pings the watchdog while reading a slot back
+struct PingReader {
+    inner: Box<dyn Read>,
+    notifier: Arc<Notifier>,
+}
+
+impl Read for PingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.notifier.ping();
+        Ok(n)
+    }
+}
+
+impl Updater {
+    pub fn new(
+        log: Logger,
+        current: InternalMeta,
+        state_dir: PathBuf,
+        source: Box<dyn Source>,
+        bootloader: Box<dyn Bootloader>,
+        disks: Box<dyn Disks>,
+        reboot: Box<dyn Reboot>,
+    ) -> Updater {
+        let status = Status {
+            current: current.uuid.clone(),
+            ..Default::default()
+        };
+        Updater {
+            notifier: Notifier::disabled(log.clone()),
+            log: log,
+            current: current,
+            state_dir: state_dir,
+            source: source,
+            bootloader: bootloader,
+            disks: disks,
+            reboot: reboot,
+            // Waiting for a route doesn't touch the server, so poll steadily
+            network_policy: RetryPolicy::fixed(Duration::minutes(10), Duration::seconds(10)),
+            meta_policy: RetryPolicy {
+                total_time: Duration::minutes(10),
+                initial: Duration::seconds(5),
+                multiplier: 2.,
+                max: Duration::minutes(2),
+                jitter: 0.5,
+            },
+            download_policy: RetryPolicy {
+                total_time: Duration::hours(1),
+                initial: Duration::seconds(30),
+                multiplier: 2.,
+                max: Duration::minutes(10),
+                jitter: 0.5,
+            },
+            reboot_delay: Duration::seconds(15),
+            leases: None,
+            lease_interval: Duration::seconds(30),
+            control: None,
+            directives: None,
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    // Check whether the last installed version failed to boot, and if so record it so it's
+    // never installed again
+    pub fn check_last_update(&self) -> Result<()> {
+        ec!(("Error checking result of last update"), {
+            let env = self.bootloader.env()?;
+            if let Some(pending) = env.get(GRUB_NEW_VAR) {
+                if *pending != self.current.uuid {
+                    err!(
+                        self.log,
+                        "Last installed version failed to boot, fell back to previous version",
+                        failed = pending,
+                        current = &self.current.uuid
+                    );
+                    record_failed_version(&self.state_dir, pending)?;
+                    if let Some(leases) = &self.leases {
+                        leases.release_held(&self.state_dir)?;
+                    }
+                    self.bootloader
+                        .unset_env(&[GRUB_ATTEMPTS_VAR, GRUB_NEW_VAR])?;
+                }
+            }
+            Ok(())
+        })
+        .context(ErrorKind::Bootloader)
+    }
+
+    // Releases a lease still held once no update is pending, in case organixm-success couldn't
+    // reach the bucket after marking the boot successful
+    fn release_settled_lease(&self) {
+        let leases = match &self.leases {
+            Some(l) => l,
+            None => return,
+        };
+        let result = self
+            .bootloader
+            .env()
+            .and_then(|env| match env.get(GRUB_NEW_VAR) {
+                Some(_) => Ok(()),
+                None => leases.release_held(&self.state_dir),
+            });
+        if let Err(e) = result {
+            warn!(
+                self.log,
+                "Failed to release reboot lease",
+                err = format!("{:?}", e)
+            );
+        }
+    }
+
+    fn reporter(&self) -> Reporter {
+        Reporter {
+            status: self.status.clone(),
+            control: self.control.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+
+    fn report<F: Fn(&mut Status)>(&self, f: F) {
+        self.reporter().report(f);
+    }
+
+    // The digest of the first `size` bytes of a slot
+    fn part_digest(&self, part: &LsblkDevice, size: u64) -> Result<String> {
+        let _copying = self.notifier.copying();
+        read_digest(
+            &mut PingReader {
+                inner: self.disks.open_read(part)?,
+                notifier: self.notifier.clone(),
+            },
+            size,
+        )
+    }
+
+    fn report_phase(&self, phase: Phase) {
+        self.report(|s| {
+            s.phase = phase;
+            if phase == Phase::Idle {
+                s.new = None;
+                s.downloaded = None;
+                s.size = None;
+                s.reboot_at = None;
+            }
+        });
+    }
+
+    // When the maintenance window next opens, if it's closed now
+    fn window_opens(&self) -> Option<DateTime<Utc>> {
+        let window = self.current.schedule.window.as_ref()?;
+        let now = Local::now();
+        let opens = window.next_open(&now);
+        if opens <= now {
+            return None;
+        }
+        Some(opens.with_timezone(&Utc))
+    }
+
+    // Waits until the source is ready (network up, clock set) or `network_policy` gives up
+    fn wait_for_network(&self) -> Result<()> {
+        retry(&self.log, &self.network_policy, || {
+            info!(self.log, "Waiting for network...");
+            if self.source.ready()? {
+                return Ok(());
+            }
+            return Err(anyhow!("Network isn't ready yet"));
+        })
+        .context(ErrorKind::Network)
+    }
+
+    // Returns the latest version if it should be installed
+    pub fn check(&self) -> Result<Option<ExternalMeta>> {
+        if self.current.hold {
+            info!(self.log, "Updates are held by the runtime config");
+            return Ok(None);
+        }
+        self.report(|s| {
+            s.phase = Phase::Checking;
+            s.error = None;
+        });
+        // Not fatal, the meta is fetched in full instead
+        let cached = match read_meta_cache(&self.state_dir) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(
+                    self.log,
+                    "Failed to read cached meta",
+                    err = format!("{:?}", e)
+                );
+                None
+            }
+        };
+        let log_cached = || {
+            if let Some(c) = &cached {
+                info!(
+                    self.log,
+                    "Last known published version",
+                    uuid = &c.meta.internal.uuid,
+                    fetched = c.fetched.to_rfc3339()
+                );
+            }
+        };
+        if let Err(e) = self.wait_for_network() {
+            log_cached();
+            return Err(e);
+        }
+
+        let etag = cached.as_ref().and_then(|c| c.etag.as_deref());
+        let fetched = match retry(&self.log, &self.meta_policy, || {
+            ec!(
+                ("Error fetching new version meta"),
+                self.source.fetch_meta(etag)
+            )
+        }) {
+            Ok(f) => f,
+            Err(e) => {
+                log_cached();
+                return Err(e);
+            }
+        };
+        let new = match fetched {
+            // The cached meta is still the latest, evaluated as if fetched in case installing
+            // it was interrupted
+            MetaFetch::NotModified => {
+                let cached = cached
+                    .ok_or_else(|| anyhow!("Source reported meta unchanged but none is cached"))
+                    .context(ErrorKind::Server)?;
+                info!(
+                    self.log,
+                    "Published meta unchanged since last check",
+                    uuid = &cached.meta.internal.uuid
+                );
+                cached.meta
+            }
+            MetaFetch::Fetched(meta, etag) => {
+                if let Err(e) = write_meta_cache(
+                    &self.state_dir,
+                    &MetaCache {
+                        etag: etag,
+                        meta: (*meta).clone(),
+                        fetched: Utc::now(),
+                    },
+                ) {
+                    warn!(self.log, "Failed to cache meta", err = format!("{:?}", e));
+                }
+                *meta
+            }
+        };
+        if self.current.uuid == new.internal.uuid {
+            info!(
+                self.log,
+                "Latest version on file server matches currently booted version",
+                uuid = &new.internal.uuid
+            );
+            return Ok(None);
+        }
+        if read_failed_versions(&self.state_dir)
+            .context(ErrorKind::Disk)?
+            .iter()
+            .any(|f| f.uuid == new.internal.uuid)
+        {
+            warn!(
+                self.log,
+                "Latest version on file server previously failed to boot, not installing",
+                uuid = &new.internal.uuid
+            );
+            return Ok(None);
+        }
+        info!(
+            self.log,
+            "A new version was found",
+            current = &self.current.uuid,
+            new = &new.internal.uuid
+        );
+        Ok(Some(new))
+    }
+
+    // The root slots and what's known about them
+    fn layout(&self) -> Result<Layout> {
+        let (root_disk, root_parts) = self.disks.root_parts().context(ErrorKind::Disk)?;
+        let (known_good, mut slots) = ec!(("Error reading slot state"), {
+            Ok((
+                self.bootloader.env()?.remove(GRUB_SAVED_VAR),
+                self.bootloader.read_slots()?,
+            ))
+        })
+        .context(ErrorKind::Bootloader)?;
+        // With nothing mounted (ex: a disk image) the current version's slot stands in for the
+        // booted root
+        let current_label = match root_parts
+            .iter()
+            .find(|p| p.mountpoint.as_deref() == Some("/"))
+        {
+            Some(p) => p.partlabel.clone().unwrap(),
+            None => slots
+                .iter()
+                .find(|s| s.meta.uuid == self.current.uuid)
+                .map(|s| s.label.clone())
+                .ok_or_else(|| anyhow!("Unable to find mounted root device"))
+                .context(ErrorKind::Disk)?,
+        };
+        if !slots
+            .iter()
+            .any(|s| s.label == current_label && s.meta.uuid == self.current.uuid)
+        {
+            // Installed before slot tracking, or state lost
+            slots.retain(|s| s.label != current_label);
+            slots.push(SlotState {
+                label: current_label.clone(),
+                meta: self.current.clone(),
+                installed: Utc::now(),
+            });
+        }
+        Ok(Layout {
+            disk: root_disk,
+            parts: root_parts,
+            current_label: current_label,
+            known_good: known_good,
+            slots: slots,
+        })
+    }
+
+    // Picks a slot and writes the new version to it. Returns None if there's no slot that can
+    // be overwritten or the version was already installed.
+    pub fn download(&self, new: &ExternalMeta) -> Result<Option<Staged>> {
+        let log = &self.log;
+
+        // Identify current root partition and pick a slot to overwrite
+        let Layout {
+            disk: root_disk,
+            parts: root_parts,
+            current_label,
+            known_good,
+            mut slots,
+        } = self.layout()?;
+        let mut candidates = vec![];
+        for part in &root_parts {
+            let label = part.partlabel.clone().unwrap();
+            if label == current_label {
+                continue;
+            }
+            let state = slots.iter().find(|s| s.label == label);
+            if let Some(state) = state {
+                if state.meta.uuid == new.internal.uuid {
+                    info!(log, "New version is already installed in another slot, must have fallen back. Aborting", slot = &label);
+                    return Ok(None);
+                }
+                if Some(&state.meta.uuid) == known_good.as_ref() {
+                    info!(
+                        log,
+                        "Slot has last known good version, skipping",
+                        slot = &label
+                    );
+                    continue;
+                }
+            }
+            // Never-written slots sort first
+            candidates.push((state.map(|s| s.installed), part));
+        }
+        candidates.sort_by_key(|c| c.0);
+        let other_part = match candidates.first() {
+            Some(c) => c.1.clone(),
+            None => {
+                info!(log, "No slot available that isn't booted or the last known good version, skipping update");
+                return Ok(None);
+            }
+        };
+        let other_label = other_part.partlabel.clone().unwrap();
+        let other_digest = self
+            .part_digest(&other_part, new.size)
+            .context(ErrorKind::Disk)?;
+        if other_digest == new.sha256 {
+            info!(
+                log,
+                "Digest of alternate partition matches new digest, must have fallen back. Aborting",
+                digest = &new.sha256
+            );
+            return Ok(None);
+        }
+        info!(log, "Hash of alternate partition", sha = other_digest);
+        info!(log, "Installing to slot", slot = &other_label);
+
+        // The slot contents are about to be invalid
+        clear_staged(&self.state_dir).context(ErrorKind::Disk)?;
+        slots.retain(|s| s.label != other_label);
+        self.bootloader
+            .write_slots(&slots)
+            .context("Error updating slot state")
+            .context(ErrorKind::Bootloader)?;
+
+        info!(log, "Downloading new image");
+        self.report(|s| {
+            s.phase = Phase::Downloading;
+            s.new = Some(new.internal.uuid.clone());
+            s.size = Some(new.size);
+        });
+        retry(log, &self.download_policy, || {
+            ec!(("Error downloading new image to {}", &other_part.path), {
+                let dest = self
+                    .disks
+                    .open_write(&other_part)
+                    .context(ErrorKind::Disk)?;
+                self.report(|s| s.downloaded = Some(0));
+                let dest = ProgressWriter {
+                    inner: dest,
+                    reporter: self.reporter(),
+                    size: new.size,
+                    written: 0,
+                    reported: 0,
+                };
+                let mut writer = Writer::new(BufWriter::new(dest), Decoder::new().unwrap());
+                let _copying = self.notifier.copying();
+                self.source.fetch_image(new, &mut writer)?;
+                writer
+                    .finish()
+                    .and_then(|_| writer.flush())
+                    .context("Failed to flush/finish output")
+                    .context(ErrorKind::Disk)?;
+                Ok(())
+            })
+        })?;
+
+        Ok(Some(Staged {
+            meta: new.clone(),
+            disk: root_disk,
+            part: other_part,
+            label: other_label,
+            current_label: current_label,
+            known_good: known_good,
+            slots: slots,
+        }))
+    }
+
+    // Check the written slot against the published digest
+    pub fn verify(&self, staged: &Staged) -> Result<()> {
+        self.report_phase(Phase::Verifying);
+        let digest = self
+            .part_digest(&staged.part, staged.meta.size)
+            .context("Error reading back new image")
+            .context(ErrorKind::Disk)?;
+        if digest != staged.meta.sha256 {
+            return Err(anyhow!(
+                "Written digest {} doesn't match reported digest on server {}",
+                digest,
+                staged.meta.sha256
+            )
+            .context(ErrorKind::Verification)
+            .context(Permanent));
+        }
+        Ok(())
+    }
+
+    // Downloads and verifies the new version and records it as staged, so it can be activated
+    // later (even after a reboot). A version that's already staged isn't downloaded again.
+    pub fn stage(&self, new: &ExternalMeta) -> Result<Option<Staged>> {
+        if let Some(staged) = self.staged()? {
+            if staged.meta.internal.uuid == new.internal.uuid && staged.meta.sha256 == new.sha256 {
+                info!(
+                    self.log,
+                    "New version is already staged",
+                    slot = &staged.label
+                );
+                self.report_staged(&staged);
+                return Ok(Some(staged));
+            }
+        }
+        let staged = match self.download(new)? {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        self.verify(&staged)?;
+        write_staged(
+            &self.state_dir,
+            &StagedVersion {
+                meta: staged.meta.clone(),
+                label: staged.label.clone(),
+                staged: Utc::now(),
+            },
+        )
+        .context(ErrorKind::Disk)?;
+        info!(
+            self.log,
+            "Staged new version",
+            uuid = &new.internal.uuid,
+            slot = &staged.label
+        );
+        self.report_staged(&staged);
+        Ok(Some(staged))
+    }
+
+    fn report_staged(&self, staged: &Staged) {
+        self.report(|s| {
+            s.phase = Phase::Staged;
+            s.new = Some(staged.meta.internal.uuid.clone());
+            s.downloaded = None;
+            s.size = None;
+        });
+    }
+
+    // The staged version if it's still intact in its slot. A staged version whose slot was
+    // booted, rewritten or no longer matches the digest is discarded.
+    pub fn staged(&self) -> Result<Option<Staged>> {
+        let record = match read_staged(&self.state_dir).context(ErrorKind::Disk)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let layout = self.layout()?;
+        let uuid = &record.meta.internal.uuid;
+        let discard = |reason: String| -> Result<Option<Staged>> {
+            warn!(
+                self.log,
+                "Discarding staged version",
+                uuid = uuid,
+                slot = &record.label,
+                reason = reason
+            );
+            clear_staged(&self.state_dir).context(ErrorKind::Disk)?;
+            Ok(None)
+        };
+        let part = match layout
+            .parts
+            .iter()
+            .find(|p| p.partlabel.as_deref() == Some(record.label.as_str()))
+        {
+            Some(p) => p.clone(),
+            None => return discard("slot no longer exists".to_string()),
+        };
+        if record.label == layout.current_label {
+            return discard("slot is booted".to_string());
+        }
+        if layout.slots.iter().any(|s| s.label == record.label) {
+            return discard("slot was written since".to_string());
+        }
+        if *uuid == self.current.uuid {
+            return discard("version is already booted".to_string());
+        }
+        if read_failed_versions(&self.state_dir)
+            .context(ErrorKind::Disk)?
+            .iter()
+            .any(|f| &f.uuid == uuid)
+        {
+            return discard("version failed to boot".to_string());
+        }
+        let digest = self
+            .part_digest(&part, record.meta.size)
+            .context("Error reading back staged image")
+            .context(ErrorKind::Disk)?;
+        if digest != record.meta.sha256 {
+            return discard(format!("slot digest changed to {}", digest));
+        }
+        Ok(Some(Staged {
+            meta: record.meta.clone(),
+            disk: layout.disk,
+            part: part,
+            label: record.label.clone(),
+            current_label: layout.current_label,
+            known_good: layout.known_good,
+            slots: layout.slots,
+        }))
+    }
+
+    // Point the bootloader at the new version, with fallback to the last known good version
+    pub fn activate(&self, staged: &Staged) -> Result<()> {
+        info!(self.log, "Updating grub");
+        let new = &staged.meta.internal;
+        ec!(("Error updating grub on {}", &staged.disk.path), {
+            let mut slots = staged.slots.clone();
+            slots.push(SlotState {
+                label: staged.label.clone(),
+                meta: new.clone(),
+                installed: Utc::now(),
+            });
+            let mut entries = vec![
+                BootEntry {
+                    label: &staged.label,
+                    meta: new,
+                },
+                BootEntry {
+                    label: &staged.current_label,
+                    meta: &self.current,
+                },
+            ];
+            let mut others = slots
+                .iter()
+                .filter(|s| s.label != staged.label && s.label != staged.current_label)
+                .collect::<Vec<_>>();
+            others.sort_by_key(|s| std::cmp::Reverse(s.installed));
+            for s in others {
+                entries.push(BootEntry {
+                    label: &s.label,
+                    meta: &s.meta,
+                });
+            }
+            self.bootloader.write_slots(&slots)?;
+            self.bootloader.install(
+                &staged.disk,
+                &BootConfig {
+                    new: new,
+                    current: &self.current,
+                    entries: entries,
+                },
+            )?;
+            // Fall back to the last known good version, which may not be the current one if
+            // it hasn't been confirmed yet
+            self.bootloader.set_env(&[
+                (
+                    GRUB_SAVED_VAR,
+                    staged.known_good.as_ref().unwrap_or(&self.current.uuid),
+                ),
+                (GRUB_NEW_VAR, &new.uuid),
+                (GRUB_ATTEMPTS_VAR, &format!("{}", new.boot_attempts.max(1))),
+            ])?;
+            Ok(())
+        })
+        .context(ErrorKind::Bootloader)?;
+        // Not fatal, a stale record is discarded since the slot is no longer unclaimed
+        if let Err(e) = clear_staged(&self.state_dir) {
+            warn!(
+                self.log,
+                "Failed to clear staged version",
+                err = format!("{:?}", e)
+            );
+        }
+        Ok(())
+    }
+
+    pub fn reboot(&self) -> Result<()> {
+        self.reboot.reboot()
+    }
+
+    // Runs new remote directives for this device once the source is ready, rebooting if one asks
+    // to. The caller should stop if `reboot` is set.
+    pub fn run_directives(&self) -> Result<Requested> {
+        let directives = match &self.directives {
+            Some(d) => d,
+            None => return Ok(Requested::default()),
+        };
+        self.wait_for_network()?;
+        let requested = ec!(("Error running directives"), directives.run())?;
+        if requested.reboot {
+            info!(self.log, "Rebooting as directed");
+            self.report_phase(Phase::Rebooting);
+            self.reboot()?;
+        }
+        Ok(requested)
+    }
+
+    // Waits for a reboot lease, if the version has them configured
+    fn acquire_lease(&self, new: &InternalMeta) -> Result<Option<HeldLease>> {
+        let leases = match &self.leases {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let config = &leases.config;
+        info!(
+            self.log,
+            "Waiting for a reboot lease",
+            group = &config.group,
+            count = config.count
+        );
+        let policy = RetryPolicy::fixed(Duration::seconds(config.wait as i64), self.lease_interval);
+        let held = retry(&self.log, &policy, || {
+            leases.try_acquire(&new.uuid)?.ok_or_else(|| {
+                anyhow!(
+                    "All {} reboot leases in group {} are held",
+                    config.count,
+                    config.group
+                )
+                .context(ErrorKind::Lease)
+            })
+        })?;
+        write_held_lease(&self.state_dir, &held).context(ErrorKind::Disk)?;
+        Ok(Some(held))
+    }
+
+    // Activates and reboots once any reboot lease is held. The lease is released if activating
+    // fails, otherwise after the new version is marked good. With a control socket, clients can't
+    // approve or postpone the reboot to before `not_before`.
+    fn activate_and_reboot(
+        &self,
+        staged: &Staged,
+        not_before: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        if let Some(control) = &self.control {
+            let reboot_at = control.reboot_at(self.reboot_delay, not_before);
+            self.report(|s| {
+                s.phase = Phase::AwaitingReboot;
+                s.new = Some(staged.meta.internal.uuid.clone());
+                s.reboot_at = reboot_at;
+            });
+            // Could wait indefinitely, let units ordered after the updater start
+            self.notifier.ready();
+            control.await_reboot();
+        }
+        if self.leases.is_some() {
+            self.report_phase(Phase::WaitingForLease);
+            self.notifier.ready();
+        }
+        let held = self.acquire_lease(&staged.meta.internal)?;
+        self.report_phase(Phase::Activating);
+        if let Err(e) = self.activate(staged) {
+            if let (Some(leases), Some(_)) = (&self.leases, held) {
+                if let Err(e) = leases.release_held(&self.state_dir) {
+                    warn!(
+                        self.log,
+                        "Failed to release lease",
+                        err = format!("{:?}", e)
+                    );
+                }
+            }
+            return Err(e);
+        }
+        self.report_phase(Phase::Rebooting);
+        // With a control socket the delay was already given to clients to postpone
+        let delay = match self.control {
+            Some(_) => Duration::zero(),
+            None => self.reboot_delay,
+        };
+        info!(
+            self.log,
+            "Grub installed successfully, rebooting",
+            delay = delay.num_seconds()
+        );
+        std::thread::sleep(delay.to_std().unwrap());
+        self.reboot()
+    }
+
+    // Runs all steps, rebooting if a new version was installed
+    pub fn run(&self) -> Result<()> {
+        self.check_last_update()?;
+        self.update()?;
+        Ok(())
+    }
+
+    // Runs the steps up to staging, leaving activation for later
+    pub fn run_stage(&self) -> Result<()> {
+        self.check_last_update()?;
+        self.stage_update()
+    }
+
+    // Activates the staged version and reboots into it, if one is staged and still intact
+    pub fn run_activate(&self) -> Result<()> {
+        self.check_last_update()?;
+        self.activate_staged()?;
+        Ok(())
+    }
+
+    // `run` without checking the last update. Returns whether it rebooted.
+    fn update(&self) -> Result<bool> {
+        ec!(
+            (
+                "Failed to update image from {}/{}",
+                self.current.bucket,
+                self.current.object_path
+            ),
+            {
+                self.release_settled_lease();
+                let staged = match self.check()? {
+                    Some(new) => self.stage(&new)?,
+                    None => None,
+                };
+                let staged = match staged {
+                    Some(s) => s,
+                    None => {
+                        self.report_phase(Phase::Idle);
+                        return Ok(false);
+                    }
+                };
+                let opens = self.window_opens();
+                if let (Some(opens), None) = (opens, &self.control) {
+                    info!(
+                        self.log,
+                        "Outside the maintenance window, leaving the new version staged",
+                        opens = opens.to_rfc3339()
+                    );
+                    return Ok(false);
+                }
+                self.activate_and_reboot(&staged, opens)?;
+                Ok(true)
+            }
+        )
+    }
+
+    // `run_stage` without checking the last update
+    fn stage_update(&self) -> Result<()> {
+        ec!(
+            (
+                "Failed to stage image from {}/{}",
+                self.current.bucket,
+                self.current.object_path
+            ),
+            {
+                self.release_settled_lease();
+                let staged = match self.check()? {
+                    Some(new) => self.stage(&new)?,
+                    None => None,
+                };
+                if staged.is_none() {
+                    self.report_phase(Phase::Idle);
+                }
+                Ok(())
+            }
+        )
+    }
+
+    // `run_activate` without checking the last update. Returns whether it rebooted.
+    fn activate_staged(&self) -> Result<bool> {
+        ec!(("Failed to activate staged version"), {
+            self.release_settled_lease();
+            if self.current.hold {
+                info!(
+                    self.log,
+                    "Updates are held by the runtime config, not activating"
+                );
+                self.report_phase(Phase::Idle);
+                return Ok(false);
+            }
+            let staged = match self.staged()? {
+                Some(s) => s,
+                None => {
+                    info!(self.log, "No version staged, nothing to activate");
+                    self.report_phase(Phase::Idle);
+                    return Ok(false);
+                }
+            };
+            info!(
+                self.log,
+                "Activating staged version",
+                uuid = &staged.meta.internal.uuid,
+                slot = &staged.label
+            );
+            self.activate_and_reboot(&staged, self.window_opens())?;
+            Ok(true)
+        })
+    }
+
+    // Runs an update (or only stages it), then keeps doing so whenever a control client asks for
+    // a check or the schedule's interval passes, and activates the staged version when a client
+    // approves it. Directives are polled in between. Failures are reported to clients rather
+    // than ending the loop, which ends once the device reboots into a new version. The last
+    // update is only checked at startup, an update pending later was installed by this process.
+    pub fn serve(&self, stage_only: bool) -> Result<()> {
+        let control = self
+            .control
+            .clone()
+            .ok_or_else(|| anyhow!("No control socket configured").context(ErrorKind::Config))?;
+        let check_interval = self
+            .current
+            .schedule
+            .interval
+            .map(|i| Duration::seconds(i as i64));
+        let mut next_check = None;
+        let mut next_poll = self.directives.as_ref().map(|_| Utc::now());
+        self.check_last_update()?;
+        let mut wake = Wake::Check;
+        loop {
+            if next_poll.map(|p| p <= Utc::now()).unwrap_or(false) {
+                let interval = self.directives.as_ref().unwrap().config.interval;
+                next_poll = Some(Utc::now() + Duration::seconds(interval as i64));
+                match self.run_directives() {
+                    Ok(r) if r.reboot => return Ok(()),
+                    Ok(r) if r.check && wake == Wake::Timeout => wake = Wake::Check,
+                    Ok(_) => {}
+                    Err(e) => warn!(
+                        self.log,
+                        "Failed to run directives",
+                        err = format!("{:?}", e)
+                    ),
+                }
+            }
+            let result = match wake {
+                Wake::Check => {
+                    next_check = check_interval.map(|i| Utc::now() + i);
+                    match stage_only {
+                        true => self.stage_update().map(|_| false),
+                        false => self.update(),
+                    }
+                }
+                Wake::Activate => self.activate_staged(),
+                Wake::Timeout => Ok(false),
+            };
+            match result {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => {
+                    err!(self.log, "Update failed", err = format!("{:?}", e));
+                    self.report(|s| {
+                        s.phase = Phase::Failed;
+                        s.error = Some(format!("{:#}", e));
+                    });
+                }
+            }
+            self.notifier.ready();
+            let until = [next_check, next_poll].into_iter().flatten().min();
+            wake = match control.wait(until) {
+                Wake::Timeout if next_check.map(|c| c <= Utc::now()).unwrap_or(false) => {
+                    Wake::Check
+                }
+                w => w,
+            };
+        }
+    }
+}
@@ -0,0 +1,1414 @@
+// Drives init and update against temp files standing in for partitions, with every spawned
+// command going through a fake runner
+use anyhow::Result;
+use chrono::Duration;
+use slog::{o, Logger};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixDatagram, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+use tempfile::TempDir;
+use tools::{
+    blockdev::block_devices,
+    control::{Control, ControlConfig},
+    directives::{Directives, DirectivesConfig, MemoryDirectiveStore, Outcome, RESET_VAR_FILE},
+    find_root_parts,
+    image::DiskImage,
+    install::{install, InitConfig},
+    lease::{read_held_lease, write_held_lease, LeaseConfig, LeaseStore, Leases, MemoryLeaseStore},
+    mirrors::{read_mirror_state, MirrorSource},
+    notify::Notifier,
+    part_type_guid, read_digest, read_failed_versions, read_meta_cache, read_slots, read_staged,
+    runner::{fake_output, FakeRunner},
+    runtime::Window,
+    system::{Grub, SystemDisks, SystemReboot},
+    updater::{MetaFetch, Source, Updater},
+    write_slots, ExternalMeta, Host, InternalMeta, RetryPolicy, SlotState, GRUB_ATTEMPTS_VAR,
+    GRUB_NEW_VAR, GRUB_SAVED_VAR,
+};
+
+const PART_SIZE: usize = 64 * 1024;
+
+fn meta(uuid: &str) -> InternalMeta {
+    serde_json::from_value(serde_json::json!({
+        "region": "us-east-1",
+        "bucket": "versions",
+        "object_path": "system",
+        "access_key": "key",
+        "secret_key": "secret",
+        "uuid": uuid,
+        "der_bzimage": format!("/nix/store/{}/bzImage", uuid),
+        "der_init": format!("/nix/store/{}/init", uuid),
+        "der_initrd": format!("/nix/store/{}/initrd", uuid),
+    }))
+    .unwrap()
+}
+
+fn image(seed: u8) -> Vec<u8> {
+    (0..PART_SIZE / 2)
+        .map(|i| (i as u8).wrapping_mul(seed))
+        .collect()
+}
+
+fn external(uuid: &str, image: &[u8]) -> ExternalMeta {
+    ExternalMeta {
+        sha256: read_digest(&mut &image[..], image.len() as u64).unwrap(),
+        size: image.len() as u64,
+        format: "zstd".to_string(),
+        internal: meta(uuid),
+        published: None,
+    }
+}
+
+const LABELS: [&str; 4] = ["boot", "organixm-a", "organixm-b", "rw"];
+
+const LINUX_FS_TYPE: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+
+// On disk guids have the first three fields little endian
+fn guid_bytes(guid: &str) -> [u8; 16] {
+    let hex = guid.replace('-', "");
+    let mut b = [0; 16];
+    for i in 0..16 {
+        b[i] = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    b[0..4].reverse();
+    b[4..6].reverse();
+    b[6..8].reverse();
+    b
+}
+
+// A gpt with just what discovery reads: the header's entry layout and named entries. Untyped
+// partitions get the generic linux filesystem type like installs from before type guids.
+fn gpt(labels: &[&str], typed: bool) -> Vec<u8> {
+    let mut disk = vec![0; 512 * 34];
+    disk[512..520].copy_from_slice(b"EFI PART");
+    disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+    disk[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+    disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+    for (i, label) in labels.iter().enumerate() {
+        let e = 1024 + i * 128;
+        let type_guid = match part_type_guid(label) {
+            Some(t) if typed => t,
+            _ => LINUX_FS_TYPE,
+        };
+        disk[e..e + 16].copy_from_slice(&guid_bytes(type_guid));
+        for (j, c) in label.encode_utf16().enumerate() {
+            disk[e + 56 + j * 2..e + 58 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+    disk
+}
+
+// Partition device name on the main disk
+fn part_name(label: &str) -> String {
+    format!(
+        "disk{}",
+        LABELS.iter().position(|l| *l == label).unwrap() + 1
+    )
+}
+
+// Adds the device file and sysfs entries for a disk with a partition per label
+fn add_disk(root: &Path, name: &str, major: u32, labels: &[&str], typed: bool) {
+    let sys_disk = root.join("sys/block").join(name);
+    fs::create_dir(&sys_disk).unwrap();
+    fs::write(root.join("dev").join(name), gpt(labels, typed)).unwrap();
+    fs::write(
+        sys_disk.join("size"),
+        format!("{}\n", PART_SIZE * (labels.len() + 1) / 512),
+    )
+    .unwrap();
+    fs::write(sys_disk.join("dev"), format!("{}:0\n", major)).unwrap();
+    for i in 0..labels.len() {
+        let part = format!("{}{}", name, i + 1);
+        File::create(root.join("dev").join(&part))
+            .unwrap()
+            .write_all(&vec![0; PART_SIZE])
+            .unwrap();
+        let sys = sys_disk.join(&part);
+        fs::create_dir(&sys).unwrap();
+        fs::write(sys.join("partition"), format!("{}\n", i + 1)).unwrap();
+        fs::write(sys.join("size"), format!("{}\n", PART_SIZE / 512)).unwrap();
+        fs::write(sys.join("dev"), format!("{}:{}\n", major, i + 1)).unwrap();
+    }
+}
+
+// A disk with a boot, rw and two root partitions, each a file in a temp dir along with a fake
+// sysfs and mountinfo. Grub's env is emulated in memory.
+struct Machine {
+    dir: TempDir,
+    host: Host,
+    runner: Arc<FakeRunner>,
+    env: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Machine {
+    fn new(mounted_root: Option<&str>) -> Machine {
+        Machine::with_types(mounted_root, true)
+    }
+
+    fn with_types(mounted_root: Option<&str>, typed: bool) -> Machine {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        for d in ["by-partlabel", "boot", "dev", "sys/block"] {
+            fs::create_dir_all(root.join(d)).unwrap();
+        }
+        add_disk(root, "disk", 8, &LABELS, typed);
+        for label in LABELS {
+            std::os::unix::fs::symlink(
+                root.join(format!("dev/{}", part_name(label))),
+                root.join("by-partlabel").join(label),
+            )
+            .unwrap();
+        }
+        let mut mountinfo = "22 1 0:21 / /proc rw,nosuid - proc proc rw\n".to_string();
+        if let Some(label) = mounted_root {
+            mountinfo.push_str(&format!(
+                "25 1 8:{} / / rw,relatime - ext4 /dev/{} rw\n",
+                LABELS.iter().position(|l| *l == label).unwrap() + 1,
+                part_name(label)
+            ));
+        }
+        fs::write(root.join("mountinfo"), mountinfo).unwrap();
+        let disk_path = root.join("dev/disk").to_string_lossy().to_string();
+
+        let env = Arc::new(Mutex::new(HashMap::new()));
+        let runner = Arc::new(FakeRunner::new({
+            let env = env.clone();
+            move |line| match line[0].as_str() {
+                "losetup" if line[1] == "--find" => Some(fake_output(0, disk_path.as_bytes())),
+                "grub-editenv" => {
+                    let mut env = env.lock().unwrap();
+                    match line[2].as_str() {
+                        "list" => {
+                            let mut out = String::new();
+                            for (k, v) in env.iter() {
+                                out.push_str(&format!("{}={}\n", k, v));
+                            }
+                            Some(fake_output(0, out.as_bytes()))
+                        }
+                        "set" => {
+                            for kv in &line[3..] {
+                                let (k, v) = kv.split_once('=').unwrap();
+                                env.insert(k.to_string(), v.to_string());
+                            }
+                            None
+                        }
+                        "unset" => {
+                            for k in &line[3..] {
+                                env.remove(k);
+                            }
+                            None
+                        }
+                        _ => Some(fake_output(1, b"")),
+                    }
+                }
+                _ => None,
+            }
+        }));
+        let host = Host {
+            runner: runner.clone(),
+            boot_dir: dir.path().join("boot"),
+            partlabel_dir: dir.path().join("by-partlabel"),
+            disk: None,
+            sys_dir: dir.path().join("sys"),
+            dev_dir: dir.path().join("dev"),
+            mountinfo_path: dir.path().join("mountinfo"),
+        };
+        Machine {
+            dir: dir,
+            host: host,
+            runner: runner,
+            env: env,
+        }
+    }
+
+    fn part(&self, label: &str) -> PathBuf {
+        self.dir.path().join("dev").join(part_name(label))
+    }
+
+    fn part_digest(&self, label: &str, size: u64) -> String {
+        read_digest(&mut File::open(self.part(label)).unwrap(), size).unwrap()
+    }
+
+    fn env(&self, k: &str) -> Option<String> {
+        self.env.lock().unwrap().get(k).cloned()
+    }
+
+    fn grub_cfg(&self) -> String {
+        fs::read_to_string(self.host.boot_dir.join("grub/grub.cfg")).unwrap()
+    }
+
+    fn updater(&self, source: impl Source + 'static) -> Updater {
+        self.updater_on(self.host.clone(), meta("current"), source)
+    }
+
+    fn updater_on(
+        &self,
+        host: Host,
+        current: InternalMeta,
+        source: impl Source + 'static,
+    ) -> Updater {
+        let log = logger();
+        let policy = RetryPolicy::fixed(Duration::milliseconds(50), Duration::milliseconds(10));
+        let mut updater = Updater::new(
+            log.clone(),
+            current,
+            self.dir.path().join("state"),
+            Box::new(source),
+            Box::new(Grub::new(log.clone(), host.clone())),
+            Box::new(SystemDisks::new(log.clone(), host)),
+            Box::new(SystemReboot::new(self.runner.clone())),
+        );
+        updater.network_policy = policy.clone();
+        updater.meta_policy = policy.clone();
+        updater.download_policy = policy;
+        updater.reboot_delay = Duration::zero();
+        updater
+    }
+}
+
+fn logger() -> Logger {
+    Logger::root(slog::Discard, o!())
+}
+
+struct FakeSource {
+    meta: ExternalMeta,
+    compressed: Vec<u8>,
+    // Etag sent with each meta fetch
+    etags: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl FakeSource {
+    fn new(uuid: &str, image: &[u8]) -> FakeSource {
+        FakeSource {
+            meta: external(uuid, image),
+            compressed: zstd::encode_all(image, 0).unwrap(),
+            etags: Default::default(),
+        }
+    }
+}
+
+impl Source for FakeSource {
+    fn ready(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+        self.etags.lock().unwrap().push(etag.map(|e| e.to_string()));
+        let current = format!("\"{}\"", self.meta.internal.uuid);
+        if etag == Some(current.as_str()) {
+            return Ok(MetaFetch::NotModified);
+        }
+        Ok(MetaFetch::Fetched(
+            Box::new(self.meta.clone()),
+            Some(current),
+        ))
+    }
+
+    fn fetch_image(&self, _meta: &ExternalMeta, dest: &mut (dyn Write + Send)) -> Result<()> {
+        dest.write_all(&self.compressed)?;
+        Ok(())
+    }
+}
+
+// A machine as left by init, booted from slot a
+fn installed_machine() -> Machine {
+    let m = Machine::new(Some("organixm-a"));
+    m.init_state(&m.host);
+    m
+}
+
+impl Machine {
+    // Boot partition contents and grub env as left by init, with the current version in slot a
+    fn init_state(&self, host: &Host) {
+        let _mount = tools::mount_boot(logger(), host).unwrap();
+        fs::create_dir(host.boot_dir.join("grub")).unwrap();
+        write_slots(
+            host,
+            &[SlotState {
+                label: "organixm-a".to_string(),
+                meta: meta("current"),
+                installed: chrono::Utc::now(),
+            }],
+        )
+        .unwrap();
+        self.env
+            .lock()
+            .unwrap()
+            .insert(GRUB_SAVED_VAR.to_string(), "current".to_string());
+    }
+}
+
+#[test]
+fn init_installs_first_version() {
+    let m = Machine::new(None);
+    let img = image(3);
+    let version_path = m.dir.path().join("version.zst");
+    fs::write(&version_path, zstd::encode_all(&img[..], 0).unwrap()).unwrap();
+
+    install(
+        &logger(),
+        &m.host,
+        &InitConfig {
+            size: 1,
+            slots: 2,
+            version: external("first", &img),
+            version_path: version_path,
+            credentials: None,
+        },
+    )
+    .unwrap();
+
+    let parted = m.runner.calls_to("parted");
+    assert_eq!(parted.len(), 1);
+    for label in ["boot", "organixm-a", "organixm-b", "rw"] {
+        assert!(
+            parted[0].iter().any(|a| a == label),
+            "{} not created",
+            label
+        );
+    }
+    let mkfs = m.runner.calls_to("mkfs.ext4");
+    assert_eq!(mkfs.len(), 2);
+    assert_eq!(
+        m.part_digest("organixm-a", img.len() as u64),
+        external("first", &img).sha256
+    );
+    let slots = read_slots(&m.host).unwrap();
+    assert_eq!(slots.len(), 1);
+    assert_eq!(slots[0].label, "organixm-a");
+    assert_eq!(slots[0].meta.uuid, "first");
+    assert!(m.grub_cfg().contains("/nix/store/first/bzImage"));
+    let grub_install = m.runner.calls_to("grub-install");
+    assert_eq!(grub_install.len(), 1);
+    assert_eq!(
+        Path::new(grub_install[0].last().unwrap()),
+        m.dir.path().join("dev/disk")
+    );
+    // Every mount is paired with an unmount
+    assert_eq!(
+        m.runner.calls_to("mount").len(),
+        m.runner.calls_to("umount").len()
+    );
+}
+
+#[test]
+fn init_rejects_bad_slot_count() {
+    let m = Machine::new(None);
+    let img = image(3);
+    let err = install(
+        &logger(),
+        &m.host,
+        &InitConfig {
+            size: 1,
+            slots: 1,
+            version: external("first", &img),
+            version_path: m.dir.path().join("version.zst"),
+            credentials: None,
+        },
+    )
+    .unwrap_err();
+    assert_eq!(tools::exit_code(&err), 2);
+    assert!(m.runner.calls().is_empty());
+}
+
+#[test]
+fn update_installs_to_other_slot() {
+    let m = installed_machine();
+    let img = image(5);
+    let source = FakeSource::new("new", &img);
+    let sha = source.meta.sha256.clone();
+
+    m.updater(source).run().unwrap();
+
+    assert_eq!(m.part_digest("organixm-b", img.len() as u64), sha);
+    assert_eq!(m.env(GRUB_SAVED_VAR).as_deref(), Some("current"));
+    assert_eq!(m.env(GRUB_NEW_VAR).as_deref(), Some("new"));
+    assert_eq!(m.env(GRUB_ATTEMPTS_VAR).as_deref(), Some("3"));
+    let slots = read_slots(&m.host).unwrap();
+    assert!(slots
+        .iter()
+        .any(|s| s.label == "organixm-b" && s.meta.uuid == "new"));
+    let grub_cfg = m.grub_cfg();
+    assert!(grub_cfg.contains("/nix/store/new/bzImage"));
+    // grubenv is only written while a new version is pending
+    assert!(!grub_cfg.contains("\nsave_env"));
+    assert!(grub_cfg.contains("        save_env organixm_attempts"));
+    assert_eq!(m.runner.calls_to("grub-install").len(), 1);
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+}
+
+#[test]
+fn update_skips_current_version() {
+    let m = installed_machine();
+    m.updater(FakeSource::new("current", &image(5)))
+        .run()
+        .unwrap();
+
+    assert!(m.runner.calls_to("grub-install").is_empty());
+    assert!(m.runner.calls_to("reboot").is_empty());
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+}
+
+#[test]
+fn update_blacklists_version_that_failed_to_boot() {
+    let m = installed_machine();
+    {
+        let mut env = m.env.lock().unwrap();
+        env.insert(GRUB_NEW_VAR.to_string(), "bad".to_string());
+        env.insert(GRUB_ATTEMPTS_VAR.to_string(), "0".to_string());
+    }
+
+    m.updater(FakeSource::new("bad", &image(7))).run().unwrap();
+
+    let failed = read_failed_versions(&m.dir.path().join("state")).unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].uuid, "bad");
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert_eq!(m.env(GRUB_ATTEMPTS_VAR), None);
+    assert!(m.runner.calls_to("grub-install").is_empty());
+    assert!(m.runner.calls_to("reboot").is_empty());
+}
+
+#[test]
+fn update_fails_on_digest_mismatch() {
+    let m = installed_machine();
+    let mut source = FakeSource::new("new", &image(5));
+    source.meta.sha256 = "0".repeat(64);
+
+    let err = m.updater(source).run().unwrap_err();
+
+    assert_eq!(tools::exit_code(&err), 6);
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("reboot").is_empty());
+}
+
+#[test]
+fn staged_version_is_activated_later() {
+    let m = installed_machine();
+    let img = image(5);
+    let state_dir = m.dir.path().join("state");
+    m.updater(FakeSource::new("new", &img)).run_stage().unwrap();
+
+    let staged = read_staged(&state_dir).unwrap().unwrap();
+    assert_eq!(staged.meta.internal.uuid, "new");
+    assert_eq!(staged.label, "organixm-b");
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("grub-install").is_empty());
+    assert!(m.runner.calls_to("reboot").is_empty());
+
+    // Staging again doesn't download, a corrupt download would fail verification
+    let mut source = FakeSource::new("new", &img);
+    source.compressed = zstd::encode_all(&image(6)[..], 0).unwrap();
+    m.updater(source).run_stage().unwrap();
+
+    // Ex: after a reboot, with no source reachable
+    let mut source = FakeSource::new("current", &img);
+    source.meta.sha256 = "0".repeat(64);
+    m.updater(source).run_activate().unwrap();
+    assert_eq!(m.env(GRUB_NEW_VAR).as_deref(), Some("new"));
+    assert!(m.grub_cfg().contains("/nix/store/new/bzImage"));
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+    assert!(read_staged(&state_dir).unwrap().is_none());
+}
+
+#[test]
+fn staged_version_is_discarded_if_slot_changes() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    m.updater(FakeSource::new("new", &image(5)))
+        .run_stage()
+        .unwrap();
+    fs::write(m.part("organixm-b"), image(6)).unwrap();
+
+    m.updater(FakeSource::new("new", &image(5)))
+        .run_activate()
+        .unwrap();
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("reboot").is_empty());
+    assert!(read_staged(&state_dir).unwrap().is_none());
+}
+
+#[test]
+fn init_on_disk_image() {
+    let m = Machine::new(None);
+    let img = image(3);
+    let version_path = m.dir.path().join("version.zst");
+    fs::write(&version_path, zstd::encode_all(&img[..], 0).unwrap()).unwrap();
+    let image_path = m.dir.path().join("disk.img");
+    File::create(&image_path).unwrap();
+
+    {
+        let disk_image = DiskImage::attach(logger(), &m.host, &image_path).unwrap();
+        let host = disk_image.host();
+        install(
+            &logger(),
+            &host,
+            &InitConfig {
+                size: 1,
+                slots: 2,
+                version: external("first", &img),
+                version_path: version_path,
+                credentials: None,
+            },
+        )
+        .unwrap();
+        let grub_install = m.runner.calls_to("grub-install");
+        assert!(grub_install[0].contains(&format!(
+            "--boot-directory={}",
+            host.boot_dir.to_string_lossy()
+        )));
+        assert_eq!(read_slots(&host).unwrap()[0].meta.uuid, "first");
+    }
+
+    // Partitions are resolved on the loop device rather than through by-partlabel
+    let mkfs = m.runner.calls_to("mkfs.ext4");
+    assert_eq!(mkfs[0][1], m.part("boot").to_string_lossy());
+    assert_eq!(mkfs[1][1], m.part("rw").to_string_lossy());
+    assert_eq!(
+        m.runner.calls_to("losetup").last().unwrap()[1..],
+        [
+            "--detach".to_string(),
+            m.dir.path().join("dev/disk").to_string_lossy().to_string()
+        ]
+    );
+    assert!(m.runner.calls_to("poweroff").is_empty());
+}
+
+#[test]
+fn update_on_disk_image() {
+    let m = Machine::new(None);
+    let image_path = m.dir.path().join("disk.img");
+    File::create(&image_path).unwrap();
+    let disk_image = DiskImage::attach(logger(), &m.host, &image_path).unwrap();
+    let host = disk_image.host();
+    m.init_state(&host);
+    let current = disk_image.current_meta().unwrap();
+    assert_eq!(current.uuid, "current");
+
+    let img = image(5);
+    let source = FakeSource::new("new", &img);
+    let sha = source.meta.sha256.clone();
+    m.updater_on(host, current, source).run().unwrap();
+
+    // Nothing is mounted, so slot a is kept as the current version's
+    assert_eq!(m.part_digest("organixm-b", img.len() as u64), sha);
+    assert_eq!(m.env(GRUB_NEW_VAR).as_deref(), Some("new"));
+    assert_eq!(disk_image.current_meta().unwrap().uuid, "new");
+}
+
+#[test]
+fn discovers_partitions_from_sysfs_and_gpt() {
+    let m = Machine::new(Some("organixm-b"));
+    let devices = block_devices(&m.host).unwrap();
+    assert_eq!(devices.len(), 1);
+    let disk = &devices[0];
+    assert!(disk.is_disk());
+    assert_eq!(disk.size as usize, PART_SIZE * 5);
+    assert_eq!(disk.mountpoint, None);
+    assert_eq!(
+        disk.children
+            .iter()
+            .map(|c| c.partlabel.clone().unwrap())
+            .collect::<Vec<_>>(),
+        LABELS
+    );
+    for (i, part) in disk.children.iter().enumerate() {
+        assert_eq!(part.path, m.part(LABELS[i]).to_string_lossy());
+        assert_eq!(part.size as usize, PART_SIZE);
+        assert_eq!(part.parttype.as_deref(), part_type_guid(LABELS[i]));
+    }
+    assert_eq!(disk.children[2].mountpoint.as_deref(), Some("/"));
+    assert_eq!(disk.children[1].mountpoint, None);
+
+    let (_, roots) = find_root_parts(&logger(), &m.host).unwrap();
+    assert_eq!(
+        roots
+            .iter()
+            .map(|r| r.partlabel.clone().unwrap())
+            .collect::<Vec<_>>(),
+        ["organixm-a", "organixm-b"]
+    );
+    assert!(m.runner.calls().is_empty());
+}
+
+#[test]
+fn only_fixed_disks_are_discovered() {
+    let m = Machine::new(None);
+    let root = m.dir.path();
+    for name in ["loop0", "sr0", "zram0", "sdb", "sdc"] {
+        add_disk(root, name, 11, &["organixm-c", "organixm-d"], true);
+    }
+    let sys = root.join("sys/block");
+    fs::create_dir(sys.join("sr0/device")).unwrap();
+    fs::write(sys.join("sr0/device/type"), "5\n").unwrap();
+    fs::write(sys.join("sdb/removable"), "1\n").unwrap();
+    fs::write(sys.join("sdc/size"), "0\n").unwrap();
+    let devices = block_devices(&m.host).unwrap();
+    assert_eq!(
+        devices.iter().map(|d| d.path.clone()).collect::<Vec<_>>(),
+        [root.join("dev/disk").to_string_lossy()]
+    );
+
+    // Unless asked for by name, ex: an image's loop device
+    let host = Host {
+        disk: Some(root.join("dev/loop0")),
+        ..m.host.clone()
+    };
+    let devices = block_devices(&host).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].type_field, "loop");
+}
+
+#[test]
+fn removable_disk_holding_root_is_discovered() {
+    // Ex: booted from a usb stick, next to another one
+    let m = Machine::new(Some("organixm-a"));
+    let root = m.dir.path();
+    add_disk(root, "sdb", 11, &["organixm-c", "organixm-d"], true);
+    let sys = root.join("sys/block");
+    fs::write(sys.join("disk/removable"), "1\n").unwrap();
+    fs::write(sys.join("sdb/removable"), "1\n").unwrap();
+    let devices = block_devices(&m.host).unwrap();
+    assert_eq!(
+        devices.iter().map(|d| d.path.clone()).collect::<Vec<_>>(),
+        [root.join("dev/disk").to_string_lossy()]
+    );
+    let (disk, roots) = find_root_parts(&logger(), &m.host).unwrap();
+    assert_eq!(disk.path, root.join("dev/disk").to_string_lossy());
+    assert_eq!(roots.len(), 2);
+}
+
+#[test]
+fn root_slots_come_from_booted_disk() {
+    let m = Machine::new(Some("organixm-b"));
+    // Ex: a usb stick with a different layout plugged in
+    add_disk(
+        m.dir.path(),
+        "adisk",
+        9,
+        &["organixm-c", "organixm-d"],
+        true,
+    );
+    let (disk, roots) = find_root_parts(&logger(), &m.host).unwrap();
+    assert_eq!(disk.path, m.dir.path().join("dev/disk").to_string_lossy());
+    assert_eq!(roots.len(), 2);
+}
+
+#[test]
+fn root_slot_labels_on_multiple_disks_are_rejected() {
+    let m = Machine::new(Some("organixm-a"));
+    // Ex: a disk cloned from another machine
+    add_disk(m.dir.path(), "adisk", 9, &LABELS, true);
+    let err = find_root_parts(&logger(), &m.host).unwrap_err();
+    assert!(format!("{:?}", err).contains("organixm-a"));
+
+    let err = m
+        .updater(FakeSource::new("new", &image(5)))
+        .run()
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("Multiple organixm partitions labeled boot"));
+    assert!(m.runner.calls_to("grub-install").is_empty());
+}
+
+#[test]
+fn root_slots_without_booted_root_need_a_single_disk() {
+    let m = Machine::new(None);
+    add_disk(
+        m.dir.path(),
+        "adisk",
+        9,
+        &["organixm-c", "organixm-d"],
+        true,
+    );
+    assert!(find_root_parts(&logger(), &m.host).is_err());
+}
+
+#[test]
+fn init_assigns_partition_types() {
+    let m = Machine::new(None);
+    let img = image(3);
+    let version_path = m.dir.path().join("version.zst");
+    fs::write(&version_path, zstd::encode_all(&img[..], 0).unwrap()).unwrap();
+    install(
+        &logger(),
+        &m.host,
+        &InitConfig {
+            size: 1,
+            slots: 2,
+            version: external("first", &img),
+            version_path: version_path,
+            credentials: None,
+        },
+    )
+    .unwrap();
+
+    let disk = m.dir.path().join("dev/disk").to_string_lossy().to_string();
+    let sfdisk = m.runner.calls_to("sfdisk");
+    for (part, label) in [(2, "boot"), (3, "organixm-a"), (4, "organixm-b"), (5, "rw")] {
+        assert!(sfdisk.contains(&vec![
+            "sfdisk".to_string(),
+            "--part-type".to_string(),
+            disk.clone(),
+            part.to_string(),
+            part_type_guid(label).unwrap().to_string(),
+        ]));
+    }
+}
+
+#[test]
+fn foreign_partitions_with_organixm_labels_are_ignored() {
+    let m = installed_machine();
+    // Ex: another os's disk that also has a partition labeled boot
+    add_disk(
+        m.dir.path(),
+        "adisk",
+        9,
+        &["boot", "organixm-a", "rw"],
+        false,
+    );
+
+    assert_eq!(m.host.part_path("boot").unwrap(), m.part("boot"));
+    let (disk, _) = find_root_parts(&logger(), &m.host).unwrap();
+    assert_eq!(disk.path, m.dir.path().join("dev/disk").to_string_lossy());
+}
+
+#[test]
+fn typed_disks_dont_hide_an_untyped_booted_install() {
+    let m = Machine::with_types(Some("organixm-a"), false);
+    // Ex: a usb stick from a newer install
+    add_disk(
+        m.dir.path(),
+        "adisk",
+        9,
+        &["organixm-a", "organixm-b"],
+        true,
+    );
+    let (disk, roots) = find_root_parts(&logger(), &m.host).unwrap();
+    assert_eq!(disk.path, m.dir.path().join("dev/disk").to_string_lossy());
+    assert_eq!(roots[1].path, m.part("organixm-b").to_string_lossy());
+}
+
+#[test]
+fn untyped_installs_are_found_by_label() {
+    let m = Machine::with_types(Some("organixm-a"), false);
+    m.init_state(&m.host);
+    let img = image(5);
+    let source = FakeSource::new("new", &img);
+    let sha = source.meta.sha256.clone();
+
+    m.updater(source).run().unwrap();
+
+    assert_eq!(m.part_digest("organixm-b", img.len() as u64), sha);
+}
+
+#[test]
+fn unchanged_meta_is_not_fetched_again() {
+    let m = installed_machine();
+    let source = FakeSource::new("current", &image(1));
+    let etags = source.etags.clone();
+    assert!(m.updater(source).check().unwrap().is_none());
+    let cached = read_meta_cache(&m.dir.path().join("state"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(cached.meta.internal.uuid, "current");
+    assert_eq!(cached.etag.as_deref(), Some("\"current\""));
+
+    let source = FakeSource {
+        etags: etags.clone(),
+        ..FakeSource::new("current", &image(1))
+    };
+    assert!(m.updater(source).check().unwrap().is_none());
+    assert_eq!(
+        *etags.lock().unwrap(),
+        vec![None, Some("\"current\"".to_string())]
+    );
+}
+
+#[test]
+fn unchanged_meta_is_still_installed_if_new() {
+    let m = installed_machine();
+    let img = image(2);
+    m.updater(FakeSource::new("second", &img)).check().unwrap();
+    // Ex: the first attempt was interrupted after fetching the meta
+    let source = FakeSource::new("second", &img);
+    let etags = source.etags.clone();
+    let new = m.updater(source).check().unwrap().unwrap();
+    assert_eq!(new.internal.uuid, "second");
+    assert_eq!(*etags.lock().unwrap(), vec![Some("\"second\"".to_string())]);
+}
+
+// Fails every request, counting them
+struct DownSource(Arc<Mutex<u32>>);
+
+impl Source for DownSource {
+    fn ready(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn fetch_meta(&self, _etag: Option<&str>) -> Result<MetaFetch> {
+        *self.0.lock().unwrap() += 1;
+        Err(anyhow::anyhow!("Connection refused"))
+    }
+
+    fn fetch_image(&self, _meta: &ExternalMeta, _dest: &mut (dyn Write + Send)) -> Result<()> {
+        *self.0.lock().unwrap() += 1;
+        Err(anyhow::anyhow!("Connection refused"))
+    }
+}
+
+// Has the meta but fails downloading the image
+struct MetaOnlySource(FakeSource);
+
+impl Source for MetaOnlySource {
+    fn ready(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+        self.0.fetch_meta(etag)
+    }
+
+    fn fetch_image(&self, _meta: &ExternalMeta, _dest: &mut (dyn Write + Send)) -> Result<()> {
+        Err(anyhow::anyhow!("Connection reset"))
+    }
+}
+
+fn mirror(
+    name: &str,
+    priority: i32,
+    source: impl Source + 'static,
+) -> (String, i32, Box<dyn Source>) {
+    (name.to_string(), priority, Box::new(source))
+}
+
+#[test]
+fn mirrors_fail_over_and_skip_unhealthy() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let img = image(2);
+    let calls = Arc::new(Mutex::new(0));
+    let source = MirrorSource::new(
+        logger(),
+        state_dir.clone(),
+        vec![
+            mirror("primary", 0, DownSource(calls.clone())),
+            mirror("mirror", 1, FakeSource::new("second", &img)),
+        ],
+    );
+    let new = m.updater(source).check().unwrap().unwrap();
+    assert_eq!(new.internal.uuid, "second");
+    assert_eq!(*calls.lock().unwrap(), 1);
+    let state = read_mirror_state(&state_dir).unwrap();
+    assert_eq!(state.mirrors["primary"].failures, 1);
+    assert_eq!(state.mirrors["mirror"].failures, 0);
+
+    // The recently failed primary is tried last
+    let source = MirrorSource::new(
+        logger(),
+        state_dir.clone(),
+        vec![
+            mirror("primary", 0, DownSource(calls.clone())),
+            mirror("mirror", 1, FakeSource::new("second", &img)),
+        ],
+    );
+    assert!(matches!(
+        source.fetch_meta(None).unwrap(),
+        MetaFetch::Fetched(..)
+    ));
+    assert_eq!(*calls.lock().unwrap(), 1);
+}
+
+#[test]
+fn stale_mirrors_are_skipped() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let now = chrono::Utc::now();
+    let published = |uuid: &str, seed: u8, at| {
+        let mut s = FakeSource::new(uuid, &image(seed));
+        s.meta.published = Some(at);
+        s
+    };
+    let source = MirrorSource::new(
+        logger(),
+        state_dir.clone(),
+        vec![mirror("mirror", 1, published("third", 3, now))],
+    );
+    source.fetch_meta(None).unwrap();
+
+    // The primary hasn't caught up with what the mirror published
+    let source = MirrorSource::new(
+        logger(),
+        state_dir.clone(),
+        vec![
+            mirror(
+                "primary",
+                0,
+                published("second", 2, now - Duration::hours(1)),
+            ),
+            mirror("mirror", 1, published("third", 3, now)),
+        ],
+    );
+    let found = m.updater(source).check().unwrap().unwrap();
+    assert_eq!(found.internal.uuid, "third");
+    let state = read_mirror_state(&state_dir).unwrap();
+    assert_eq!(state.mirrors["primary"].failures, 1);
+    assert_eq!(state.newest_published, Some(now));
+}
+
+#[test]
+fn mirror_images_must_match_the_meta_digest() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    // Same version but a different image
+    let mut other = FakeSource::new("second", &image(3));
+    other.meta.sha256 = "0000".to_string();
+    let source = MirrorSource::new(
+        logger(),
+        state_dir.clone(),
+        vec![
+            mirror(
+                "primary",
+                0,
+                MetaOnlySource(FakeSource::new("second", &image(2))),
+            ),
+            mirror("mirror", 1, other),
+        ],
+    );
+    let meta = external("second", &image(2));
+    let mut sink = vec![];
+    source.fetch_meta(None).unwrap();
+    // One mirror per attempt
+    assert!(
+        format!("{:#}", source.fetch_image(&meta, &mut sink).unwrap_err())
+            .contains("Connection reset")
+    );
+    assert!(
+        format!("{:#}", source.fetch_image(&meta, &mut sink).unwrap_err()).contains("digest 0000")
+    );
+    assert!(sink.is_empty());
+    let state = read_mirror_state(&state_dir).unwrap();
+    assert_eq!(state.mirrors["primary"].failures, 1);
+    assert_eq!(state.mirrors["mirror"].failures, 1);
+}
+
+// A device's leases, in a bucket shared by several devices
+fn leases(store: &Arc<MemoryLeaseStore>, holder: &str, count: u32, ttl: u64) -> Leases {
+    let config: LeaseConfig = serde_json::from_value(serde_json::json!({
+        "group": "venue",
+        "count": count,
+        "ttl": ttl,
+        "wait": 0,
+    }))
+    .unwrap();
+    Leases::new(
+        logger(),
+        config,
+        holder.to_string(),
+        Box::new(store.clone()),
+    )
+}
+
+#[test]
+fn update_waits_for_a_reboot_lease() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let store = Arc::new(MemoryLeaseStore::default());
+    let other = leases(&store, "other", 1, 600);
+    let held = other.try_acquire("new").unwrap().unwrap();
+
+    let mut updater = m.updater(FakeSource::new("new", &image(5)));
+    updater.leases = Some(leases(&store, "device", 1, 600));
+    updater.lease_interval = Duration::milliseconds(1);
+    let err = updater.run().unwrap_err();
+    assert_eq!(tools::exit_code(&err), 10);
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("reboot").is_empty());
+    // Left staged for the next attempt
+    assert!(read_staged(&state_dir).unwrap().is_some());
+
+    other.release(&held).unwrap();
+    updater.run_activate().unwrap();
+    assert_eq!(m.env(GRUB_NEW_VAR).as_deref(), Some("new"));
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+    let held = read_held_lease(&state_dir).unwrap().unwrap();
+    assert_eq!(store.get(&held.key).unwrap().unwrap().0.holder, "device");
+
+    // After the new version is marked good
+    leases(&store, "device", 1, 600)
+        .release_held(&state_dir)
+        .unwrap();
+    assert!(read_held_lease(&state_dir).unwrap().is_none());
+    assert!(store.get(&held.key).unwrap().is_none());
+}
+
+#[test]
+fn update_releases_lease_left_held_after_success() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let store = Arc::new(MemoryLeaseStore::default());
+    let device = leases(&store, "device", 1, 600);
+    let held = device.try_acquire("current").unwrap().unwrap();
+    write_held_lease(&state_dir, &held).unwrap();
+
+    let mut updater = m.updater(FakeSource::new("current", &image(5)));
+    updater.leases = Some(device);
+    updater.run().unwrap();
+    assert!(read_held_lease(&state_dir).unwrap().is_none());
+    assert!(store.get(&held.key).unwrap().is_none());
+}
+
+fn control(m: &Machine, require_approval: bool) -> Arc<Control> {
+    let config: ControlConfig = serde_json::from_value(serde_json::json!({
+        "path": m.dir.path().join("control.sock"),
+        "require_approval": require_approval,
+    }))
+    .unwrap();
+    let control = Control::new(logger(), config, "current");
+    control.listen().unwrap();
+    control
+}
+
+// Sends a request and reads lines until its response
+fn control_request(
+    stream: &mut BufReader<UnixStream>,
+    request: serde_json::Value,
+) -> serde_json::Value {
+    let mut line = serde_json::to_vec(&request).unwrap();
+    line.push(b'\n');
+    stream.get_mut().write_all(&line).unwrap();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+        if response.get("event").is_none() {
+            return response;
+        }
+    }
+}
+
+#[test]
+fn control_socket_reports_progress_and_waits_for_approval() {
+    let m = installed_machine();
+    let control = control(&m, true);
+    let mut client = BufReader::new(UnixStream::connect(&control.config.path).unwrap());
+    let response = control_request(&mut client, serde_json::json!({"cmd": "subscribe"}));
+    assert_eq!(response["ok"], true);
+    assert_eq!(response["status"]["phase"], "idle");
+    assert_eq!(response["status"]["current"], "current");
+
+    let events = thread::spawn(move || {
+        let mut events = vec![];
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            if event.get("event").is_none() {
+                continue;
+            }
+            let status = event["status"].clone();
+            events.push(status.clone());
+            match status["phase"].as_str().unwrap() {
+                "awaiting_reboot" => {
+                    assert!(status["reboot_at"].is_null());
+                    let response =
+                        control_request(&mut client, serde_json::json!({"cmd": "approve"}));
+                    assert_eq!(response["ok"], true);
+                }
+                "rebooting" => return events,
+                _ => {}
+            }
+        }
+    });
+
+    let img = image(5);
+    let mut updater = m.updater(FakeSource::new("new", &img));
+    updater.control = Some(control.clone());
+    updater.run().unwrap();
+    let events = events.join().unwrap();
+    let phases = events
+        .iter()
+        .map(|e| e["phase"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    for phase in [
+        "checking",
+        "downloading",
+        "verifying",
+        "staged",
+        "awaiting_reboot",
+        "activating",
+        "rebooting",
+    ] {
+        assert!(phases.contains(&phase), "{} not in {:?}", phase, phases);
+    }
+    let downloaded = events
+        .iter()
+        .filter_map(|e| e["downloaded"].as_u64())
+        .max()
+        .unwrap();
+    assert_eq!(downloaded, img.len() as u64);
+    assert_eq!(events.last().unwrap()["new"], "new");
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+}
+
+#[test]
+fn control_approval_still_waits_for_a_reboot_lease() {
+    let m = installed_machine();
+    let store = Arc::new(MemoryLeaseStore::default());
+    let other = leases(&store, "other", 1, 600);
+    other.try_acquire("new").unwrap().unwrap();
+    let control = control(&m, true);
+    let mut client = BufReader::new(UnixStream::connect(&control.config.path).unwrap());
+    control_request(&mut client, serde_json::json!({"cmd": "subscribe"}));
+    let approver = thread::spawn(move || loop {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        if event["status"]["phase"] == "awaiting_reboot" {
+            let response = control_request(&mut client, serde_json::json!({"cmd": "approve"}));
+            assert_eq!(response["ok"], true);
+            return;
+        }
+    });
+
+    let mut updater = m.updater(FakeSource::new("new", &image(5)));
+    updater.control = Some(control);
+    updater.leases = Some(leases(&store, "device", 1, 600));
+    updater.lease_interval = Duration::milliseconds(1);
+    let err = updater.run().unwrap_err();
+    approver.join().unwrap();
+    assert_eq!(tools::exit_code(&err), 10);
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("reboot").is_empty());
+}
+
+#[test]
+fn control_socket_rejects_requests_out_of_turn() {
+    let m = installed_machine();
+    let control = control(&m, false);
+    let mut client = BufReader::new(UnixStream::connect(&control.config.path).unwrap());
+    for request in [
+        serde_json::json!({"cmd": "approve"}),
+        serde_json::json!({"cmd": "postpone", "seconds": 60}),
+        serde_json::json!({"cmd": "reboot"}),
+    ] {
+        let response = control_request(&mut client, request);
+        assert_eq!(response["ok"], false);
+        assert!(response["error"].is_string());
+    }
+    let response = control_request(&mut client, serde_json::json!({"cmd": "status"}));
+    assert_eq!(response["ok"], true);
+    let mode = fs::metadata(&control.config.path)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn serve_ends_after_rebooting_into_new_version() {
+    let m = installed_machine();
+    let mut updater = m.updater(FakeSource::new("new", &image(5)));
+    updater.control = Some(control(&m, false));
+    updater.serve(false).unwrap();
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+    // Still pending for the next boot rather than taken for a failed one
+    assert_eq!(m.env(GRUB_NEW_VAR).as_deref(), Some("new"));
+    assert!(read_failed_versions(&m.dir.path().join("state"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn update_notifies_systemd() {
+    let m = installed_machine();
+    let path = m.dir.path().join("notify.sock");
+    let systemd = UnixDatagram::bind(&path).unwrap();
+    systemd
+        .set_read_timeout(Some(std::time::Duration::from_millis(100)))
+        .unwrap();
+
+    let mut updater = m.updater(FakeSource::new("new", &image(5)));
+    updater.notifier = Notifier::open(
+        logger(),
+        path.to_str().unwrap(),
+        Some(std::time::Duration::from_secs(60)),
+    )
+    .unwrap();
+    updater.control = Some(control(&m, false));
+    updater.run().unwrap();
+
+    let mut messages = vec![];
+    let mut buf = [0; 1024];
+    while let Ok(n) = systemd.recv(&mut buf) {
+        messages.push(String::from_utf8(buf[..n].to_vec()).unwrap());
+    }
+    for message in [
+        "STATUS=Checking for a new version",
+        &format!(
+            "STATUS=Downloading new 100% ({0}/{0} bytes)",
+            image(5).len()
+        ),
+        "STATUS=Verifying new",
+        "WATCHDOG=1",
+        "READY=1",
+        "STATUS=Rebooting into new",
+    ] {
+        assert!(
+            messages.iter().any(|m| m == message),
+            "{} not in {:?}",
+            message,
+            messages
+        );
+    }
+    // Ready once the updater starts waiting to reboot
+    let ready = messages.iter().position(|m| m == "READY=1").unwrap();
+    assert!(messages[ready - 1].starts_with("STATUS=Rebooting into new at "));
+    assert_eq!(messages.iter().filter(|m| *m == "READY=1").count(), 1);
+}
+
+#[test]
+fn held_or_closed_window_leaves_versions_uninstalled() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let mut current = meta("current");
+    current.hold = true;
+    m.updater_on(m.host.clone(), current, FakeSource::new("new", &image(5)))
+        .run()
+        .unwrap();
+    assert!(read_staged(&state_dir).unwrap().is_none());
+    assert!(read_slots(&m.host)
+        .unwrap()
+        .iter()
+        .all(|s| s.label == "organixm-a"));
+
+    // Staged but not activated until the window opens
+    let now = chrono::Local::now();
+    let mut current = meta("current");
+    current.schedule.window = Some(Window {
+        start: (now + Duration::hours(2)).format("%H:%M").to_string(),
+        end: (now + Duration::hours(3)).format("%H:%M").to_string(),
+    });
+    m.updater_on(m.host.clone(), current, FakeSource::new("new", &image(5)))
+        .run()
+        .unwrap();
+    assert!(read_staged(&state_dir).unwrap().is_some());
+    assert_eq!(m.env(GRUB_NEW_VAR), None);
+    assert!(m.runner.calls_to("reboot").is_empty());
+}
+
+// A device's directives, in a bucket also written by the operator
+fn directives(
+    store: &Arc<MemoryDirectiveStore>,
+    device: &str,
+    state_dir: PathBuf,
+    runner: Arc<FakeRunner>,
+) -> Directives {
+    let config: DirectivesConfig = serde_json::from_value(serde_json::json!({
+        "credentials": {"type": "file"},
+    }))
+    .unwrap();
+    Directives::new(
+        logger(),
+        config,
+        device.to_string(),
+        state_dir,
+        Box::new(store.clone()),
+        runner,
+    )
+}
+
+// Not ready for the given number of checks, ex: while the network comes up
+struct LateSource(Arc<Mutex<u32>>, FakeSource);
+
+impl Source for LateSource {
+    fn ready(&self) -> Result<bool> {
+        let mut unready = self.0.lock().unwrap();
+        if *unready == 0 {
+            return Ok(true);
+        }
+        *unready -= 1;
+        Ok(false)
+    }
+
+    fn fetch_meta(&self, etag: Option<&str>) -> Result<MetaFetch> {
+        self.1.fetch_meta(etag)
+    }
+
+    fn fetch_image(&self, meta: &ExternalMeta, dest: &mut (dyn Write + Send)) -> Result<()> {
+        self.1.fetch_image(meta, dest)
+    }
+}
+
+#[test]
+fn directives_wait_for_the_source_to_be_ready() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let store = Arc::new(MemoryDirectiveStore::default());
+    store.publish(
+        "dev1",
+        serde_json::json!([
+            { "id": "a", "action": "check", "issued": chrono::Utc::now().to_rfc3339() },
+        ]),
+    );
+
+    // Never ready within the policy
+    let unready = Arc::new(Mutex::new(u32::MAX));
+    let mut updater = m.updater(LateSource(unready, FakeSource::new("new", &image(5))));
+    updater.directives = Some(directives(
+        &store,
+        "dev1",
+        state_dir.clone(),
+        m.runner.clone(),
+    ));
+    let err = updater.run_directives().unwrap_err();
+    assert_eq!(tools::exit_code(&err), 3);
+    assert!(store.result("dev1", "a").is_none());
+
+    let unready = Arc::new(Mutex::new(2));
+    let mut updater = m.updater(LateSource(
+        unready.clone(),
+        FakeSource::new("new", &image(5)),
+    ));
+    updater.directives = Some(directives(
+        &store,
+        "dev1",
+        state_dir.clone(),
+        m.runner.clone(),
+    ));
+    assert!(updater.run_directives().unwrap().check);
+    assert_eq!(*unready.lock().unwrap(), 0);
+    assert_eq!(store.result("dev1", "a").unwrap().outcome, Outcome::Done);
+}
+
+#[test]
+fn reset_var_directive_reboots() {
+    let m = installed_machine();
+    let state_dir = m.dir.path().join("state");
+    let store = Arc::new(MemoryDirectiveStore::default());
+    store.publish(
+        "dev1",
+        serde_json::json!([
+            { "id": "a", "action": "reset_var", "issued": chrono::Utc::now().to_rfc3339() },
+        ]),
+    );
+    let mut updater = m.updater(FakeSource::new("new", &image(5)));
+    updater.directives = Some(directives(
+        &store,
+        "dev1",
+        state_dir.clone(),
+        m.runner.clone(),
+    ));
+    assert!(updater.run_directives().unwrap().reboot);
+    assert!(state_dir.join(RESET_VAR_FILE).exists());
+    assert_eq!(m.runner.calls_to("reboot").len(), 1);
+    assert_eq!(store.result("dev1", "a").unwrap().outcome, Outcome::Done);
+}